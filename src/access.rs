@@ -0,0 +1,114 @@
+// Access log with bytes actually sent, duration, and whether the client went away before the end,
+// as Content-Length overestimates traffic of aborted responses.
+
+use std::{
+    net::IpAddr,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Instant,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{Method, StatusCode, Uri, Version},
+};
+use hyper::body::{Frame, SizeHint};
+
+pub struct Record {
+    // None if unknown, like served without ConnectInfo
+    pub client: Option<IpAddr>,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub status: StatusCode,
+    pub content_length: Option<u64>,
+    pub start: Instant,
+}
+
+impl Record {
+    fn log(self, sent: u64, aborted: bool) {
+        let Self {
+            client,
+            method,
+            uri,
+            version,
+            status,
+            content_length,
+            start,
+        } = self;
+        let client = client.map_or("-".to_string(), |ip| ip.to_string());
+        let content_length = content_length.map_or("-".to_string(), |len| len.to_string());
+        tracing::info!(
+            target: "yadex::access",
+            "{client} \"{method} {uri} {version:?}\" {} {sent}/{content_length} {}ms{}",
+            status.as_u16(),
+            start.elapsed().as_millis(),
+            if aborted { " aborted" } else { "" }
+        );
+    }
+}
+
+// Logs the record once the body is sent, or dropped before that (the client went away).
+pub struct LoggedBody {
+    inner: Body,
+    record: Option<Record>,
+    sent: u64,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, record: Record) -> Self {
+        Self {
+            inner,
+            record: Some(record),
+            sent: 0,
+        }
+    }
+
+    fn finish(&mut self, aborted: bool) {
+        if let Some(record) = self.record.take() {
+            record.log(self.sent, aborted);
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.sent += data.len() as u64;
+                }
+                if this.inner.is_end_stream() {
+                    this.finish(false);
+                }
+            }
+            Some(Err(_)) => this.finish(true),
+            None => this.finish(false),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // Empty bodies may never be polled
+        let aborted = !self.inner.is_end_stream();
+        self.finish(aborted);
+    }
+}
@@ -0,0 +1,100 @@
+// Static assets of templates (CSS, JS, images), loaded into memory at startup,
+// so that they are served regardless of sandboxing of the data root.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+pub struct Asset {
+    pub content: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+#[derive(Default)]
+pub struct Assets {
+    // Keyed by paths relative to assets dir, like "css/theme.css"
+    files: HashMap<String, Asset>,
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Assets {
+    // Hidden files are skipped. Symlinks are followed, but directories are loaded once, so that
+    // symlinks to ancestors don't loop forever.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut assets = Self::default();
+        let meta = fs::metadata(dir)?;
+        let mut visited = HashSet::from([(meta.dev(), meta.ino())]);
+        let mut pending = vec![String::new()];
+        while let Some(prefix) = pending.pop() {
+            for entry in fs::read_dir(dir.join(&prefix))? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let key = match prefix.as_str() {
+                    "" => name,
+                    prefix => format!("{prefix}/{name}"),
+                };
+                let path = entry.path();
+                let meta = fs::metadata(&path)?;
+                if meta.is_dir() {
+                    if visited.insert((meta.dev(), meta.ino())) {
+                        pending.push(key);
+                    }
+                } else {
+                    let asset = Asset {
+                        content: fs::read(&path)?,
+                        content_type: content_type(&path),
+                    };
+                    assets.files.insert(key, asset);
+                }
+            }
+        }
+        Ok(assets)
+    }
+
+    pub fn insert(&mut self, path: &str, content: &[u8]) {
+        let asset = Asset {
+            content: content.to_vec(),
+            content_type: content_type(Path::new(path)),
+        };
+        self.files.insert(path.to_string(), asset);
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Asset> {
+        self.files.get(path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
@@ -0,0 +1,96 @@
+// Audit log of rejected and suspicious requests, as JSON lines in a file of its own, so that
+// reviewing them doesn't require sifting through other logs.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use axum::http::{Method, StatusCode, Uri};
+use serde::Serialize;
+
+use crate::config::AuditConfig;
+
+// Attached to responses by handlers and middlewares, and written by the audit middleware.
+#[derive(Clone)]
+pub struct Audited {
+    // Like "disallowed_host"
+    pub event: &'static str,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    event: &'static str,
+    detail: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<IpAddr>,
+    method: &'a str,
+    uri: String,
+    host: &'a str,
+    status: u16,
+}
+
+pub struct Request<'a> {
+    // None if unknown, like served without ConnectInfo
+    pub client: Option<IpAddr>,
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub host: &'a str,
+}
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    // Appended to, and never reopened, so rotate it with copytruncate.
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.file)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, req: &Request, status: StatusCode, audited: &Audited) {
+        let line = Line {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            event: audited.event,
+            detail: &audited.detail,
+            client: req.client,
+            method: req.method.as_str(),
+            uri: req.uri.to_string(),
+            host: req.host,
+            status: status.as_u16(),
+        };
+        let Ok(mut line) = serde_json::to_vec(&line) else {
+            return;
+        };
+        line.push(b'\n');
+        // A single write, so lines are not interleaved
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            tracing::warn!("failed to write audit log: {e}");
+        }
+    }
+}
+
+// Whether the request tries going up with "..", in its path or query. Such paths are resolved
+// safely anyway, but who tries is worth knowing.
+pub fn is_traversal(uri: &Uri) -> bool {
+    let is_parent = |s: &str| {
+        urlencoding::decode(&s.replace('+', " "))
+            .map(|s| s.split(['/', '\\']).any(|seg| seg == ".."))
+            .unwrap_or(false)
+    };
+    is_parent(uri.path())
+        || uri.query().is_some_and(|q| {
+            q.split('&')
+                .any(|pair| is_parent(pair.split_once('=').map_or(pair, |(_, v)| v)))
+        })
+}
@@ -0,0 +1,142 @@
+// `yadex bench`: times each step of listing a directory, to compare options like stat_concurrency
+// and cache_ttl on slow storage.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, http::Request};
+use color_eyre::eyre::WrapErr;
+use futures_util::StreamExt;
+use tokio_stream::wrappers::ReadDirStream;
+use tower_service::Service;
+use yadex::{
+    config::{Config, Security},
+    server::{direntry_info, fs_root, limit_for, path_to_href, prefix_limits, to_limit},
+};
+
+use crate::cmdline::{BenchArgs, Cmdline};
+
+#[derive(Default)]
+struct Timings(Vec<Duration>);
+
+impl Timings {
+    fn time<T>(&mut self, start: Instant, value: T) -> T {
+        self.0.push(start.elapsed());
+        value
+    }
+
+    // Like "min 1.2ms, median 3.4ms, max 5.6ms"
+    fn summary(&mut self) -> String {
+        self.0.sort();
+        let ms = |d: &Duration| format!("{:.1}ms", d.as_secs_f64() * 1000.0);
+        match (self.0.first(), self.0.get(self.0.len() / 2), self.0.last()) {
+            (Some(min), Some(median), Some(max)) => {
+                format!("min {}, median {}, max {}", ms(min), ms(median), ms(max))
+            }
+            _ => "-".to_string(),
+        }
+    }
+}
+
+pub async fn bench(cmdline: &Cmdline, args: &BenchArgs, config: Config) -> color_eyre::Result<()> {
+    let Some(mut config) = config.into_instances().into_iter().nth(args.instance) else {
+        color_eyre::eyre::bail!("no instance {}", args.instance);
+    };
+    // Nothing is served, so no sandboxing. Only what lists the directory is kept, and the index
+    // is left out, as its scans would compete with the benchmark.
+    config.service.security = Security::None;
+    config.network.access_log = false;
+    config.network.allowed_hosts.clear();
+    (config.maintenance, config.audit, config.index) = (None, None, None);
+    (config.user_agents, config.hotlink, config.geoip) = (None, None, None);
+
+    let root = fs_root(&config.service)?;
+    let relative = Path::new(args.dir.trim_start_matches('/'));
+    let dir = root.join(relative);
+    let limit = limit_for(
+        to_limit(config.service.limit),
+        &prefix_limits(&config.service),
+        &Path::new(".").join(relative),
+    );
+    let concurrency = config.service.stat_concurrency.max(1);
+    let fast_threshold = config.service.fast_listing_threshold;
+    let uri = format!("{}/", path_to_href(relative).trim_end_matches('/'));
+    let (mut router, _) = crate::build_router(cmdline, config)?;
+
+    let (mut read_dir, mut stat, mut sort) =
+        (Timings::default(), Timings::default(), Timings::default());
+    let (mut first_page, mut page) = (Timings::default(), Timings::default());
+    let (mut entries, mut page_size) = (0, 0);
+    for i in 0..args.iterations.max(1) {
+        let start = Instant::now();
+        let names: Vec<_> = ReadDirStream::new(
+            tokio::fs::read_dir(&dir)
+                .await
+                .wrap_err_with(|| format!("failed to read {}", dir.display()))?,
+        )
+        // Like serving, hidden entries count towards the limit
+        .take(limit)
+        .filter_map(async |entry| entry.ok())
+        .filter(|entry| std::future::ready(!entry.file_name().as_encoded_bytes().starts_with(b".")))
+        .collect()
+        .await;
+        let names = read_dir.time(start, names);
+        entries = names.len();
+
+        let start = Instant::now();
+        let mut infos: Vec<_> = futures_util::stream::iter(names)
+            .map(direntry_info)
+            .buffer_unordered(concurrency)
+            .filter_map(async |info| info)
+            .map(|(d, meta, _)| {
+                (
+                    !meta.is_dir(),
+                    d.file_name().to_string_lossy().to_lowercase(),
+                )
+            })
+            .collect()
+            .await;
+        stat.time(start, ());
+
+        let start = Instant::now();
+        infos.sort();
+        sort.time(start, ());
+
+        let start = Instant::now();
+        let req = Request::get(&uri).body(Body::empty())?;
+        let resp = router.call(req).await?;
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+        if !status.is_success() {
+            color_eyre::eyre::bail!("{uri} gives {status}: {}", String::from_utf8_lossy(&body));
+        }
+        page_size = body.len();
+        match i {
+            0 => first_page.time(start, ()),
+            _ => page.time(start, ()),
+        }
+    }
+
+    let limited = match limit {
+        usize::MAX => String::new(),
+        limit => format!(", limit = {limit}"),
+    };
+    println!("{} ({entries} entries{limited})", dir.display());
+    println!("  readdir      {}", read_dir.summary());
+    println!(
+        "  stat         {} (stat_concurrency = {concurrency})",
+        stat.summary()
+    );
+    if fast_threshold > 0 && entries >= fast_threshold {
+        println!("               skipped when serving, as fast_listing_threshold is reached");
+    }
+    println!("  sort         {}", sort.summary());
+    println!(
+        "  page, first  {} ({page_size} bytes)",
+        first_page.summary()
+    );
+    println!("  page, later  {} (from cache, if enabled)", page.summary());
+    Ok(())
+}
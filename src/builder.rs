@@ -0,0 +1,153 @@
+// Building the router of an instance, for the yadex binary and services embedding yadex.
+
+use std::path::PathBuf;
+
+use crate::{
+    config::{NetworkConfig, ServiceConfig, Theme},
+    server::{App, Components, Template, YadexError},
+};
+
+// Namespace of the builder, like `yadex::Router::builder().root("/srv/mirror").build()`.
+pub struct Router;
+
+impl Router {
+    pub fn builder() -> RouterBuilder {
+        RouterBuilder::default()
+    }
+}
+
+// Options not set are defaults of config files (see etc/config.toml), with the built-in classic
+// theme as template, and no limit of entries (listing_memory_limit still applies).
+pub struct RouterBuilder {
+    service: ServiceConfig,
+    network: NetworkConfig,
+    template: Option<Template>,
+    components: Components,
+    connect_info: bool,
+}
+
+impl Default for RouterBuilder {
+    fn default() -> Self {
+        // Through serde, so that defaults are the same as of config files
+        let service = serde_json::json!({ "limit": 0, "root": ".", "security": "none" });
+        let network = serde_json::json!({ "address": "127.0.0.1", "port": 0 });
+        Self {
+            service: serde_json::from_value(service).expect("required fields are given"),
+            network: serde_json::from_value(network).expect("required fields are given"),
+            template: None,
+            components: Components::default(),
+            connect_info: true,
+        }
+    }
+}
+
+impl RouterBuilder {
+    // Directory to list, overriding root of service().
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.service.root = root.into();
+        self
+    }
+
+    // Max number of entries listed, 0 for unlimited.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.service.limit = limit;
+        self
+    }
+
+    // Like Template::from_config, or Template::theme.
+    pub fn template(mut self, template: Template) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    // All of [service]. Call root() and limit() after it, if at all.
+    pub fn service(mut self, config: ServiceConfig) -> Self {
+        self.service = config;
+        self
+    }
+
+    // Options of [network] applying to requests, like real_ip_header and allowed_hosts. Address
+    // and port are up to whoever serves the router.
+    pub fn network(mut self, config: NetworkConfig) -> Self {
+        self.network = config;
+        self
+    }
+
+    // Optional components, like status and redirects.
+    pub fn components(mut self, components: Components) -> Self {
+        self.components = components;
+        self
+    }
+
+    // Whether the router is served with ConnectInfo<SocketAddr> (true by default). Without it (and
+    // real_ip_header), clients are unknown, so build() refuses User-Agent rules that throttle, and
+    // GeoIP, and the audit log records no client.
+    pub fn connect_info(mut self, served_with: bool) -> Self {
+        self.connect_info = served_with;
+        self
+    }
+
+    // Must be called within tokio runtime, as background tasks (like cache invalidation) are
+    // spawned. Clients are told by ConnectInfo<SocketAddr> of connections (or real_ip_header), so
+    // serve it with `into_make_service_with_connect_info::<SocketAddr>()`, or tell connect_info().
+    // Chroots with security = "chroot".
+    pub fn build(self) -> Result<axum::Router, YadexError> {
+        let template = match self.template {
+            Some(template) => template,
+            None => Template::theme(Theme::Classic).expect("built-in themes compile"),
+        };
+        App::router(
+            self.service,
+            &self.network,
+            template,
+            self.components,
+            self.connect_info,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Router;
+    use crate::{server::Components, user_agent::UserAgentRules};
+
+    fn throttling() -> Components {
+        let rules = serde_json::json!({ "rules": [{ "pattern": "curl", "action": "throttle" }] });
+        let rules = UserAgentRules::from_config(serde_json::from_value(rules).unwrap()).unwrap();
+        Components {
+            user_agents: Some(Arc::new(rules)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn throttling_needs_clients() {
+        let root = std::env::temp_dir();
+        let without = Router::builder()
+            .root(&root)
+            .components(throttling())
+            .connect_info(false)
+            .build();
+        assert!(without.is_err());
+
+        let mut network: crate::config::NetworkConfig =
+            serde_json::from_value(serde_json::json!({ "address": "127.0.0.1", "port": 0 }))
+                .unwrap();
+        network.real_ip_header = Some("X-Real-IP".to_string());
+        let by_header = Router::builder()
+            .root(&root)
+            .network(network)
+            .components(throttling())
+            .connect_info(false)
+            .build();
+        assert!(by_header.is_ok());
+
+        let with = Router::builder()
+            .root(&root)
+            .components(throttling())
+            .build();
+        assert!(with.is_ok());
+    }
+}
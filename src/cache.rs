@@ -0,0 +1,112 @@
+// In-memory cache of directory entries, to avoid listing hot directories again and again.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use moka::notification::RemovalCause;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::watcher::DirWatcher;
+
+pub struct ListingCache<T> {
+    // Listings are tagged with the generation of their prepare()
+    cache: moka::sync::Cache<PathBuf, (u64, Arc<T>)>,
+    // Cached directories are watched, and invalidated when changed
+    watcher: Option<Arc<DirWatcher>>,
+    // Latest generation prepared for each directory. Evicting an older listing (like an expired one
+    // evicted while listing the directory again) must not unwatch it.
+    generations: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    next_generation: AtomicU64,
+}
+
+impl<T: Send + Sync + 'static> ListingCache<T> {
+    // Capacity is the max total number of entries of all cached directories, as counted by len.
+    pub fn new(
+        ttl: Duration,
+        capacity: u64,
+        watcher: Option<Arc<DirWatcher>>,
+        len: fn(&T) -> usize,
+    ) -> Self {
+        let mut builder = moka::sync::Cache::builder()
+            .time_to_live(ttl)
+            .max_capacity(capacity)
+            .weigher(move |_, (_, listing): &(u64, Arc<T>)| {
+                len(listing).try_into().unwrap_or(u32::MAX).max(1)
+            });
+        let generations = Arc::new(Mutex::new(HashMap::new()));
+        if let Some(watcher) = &watcher {
+            let (watcher, generations) = (watcher.clone(), generations.clone());
+            builder = builder.eviction_listener(
+                move |path: Arc<PathBuf>, (generation, _): (u64, Arc<T>), cause| {
+                    if cause == RemovalCause::Replaced {
+                        return;
+                    }
+                    let mut generations = generations.lock().unwrap();
+                    if generations.get(path.as_ref()) == Some(&generation) {
+                        generations.remove(path.as_ref());
+                        watcher.unwatch(&path);
+                    }
+                },
+            );
+        }
+        Self {
+            cache: builder.build(),
+            watcher,
+            generations,
+            next_generation: AtomicU64::new(0),
+        }
+    }
+
+    // Invalidates changed directories in background.
+    pub fn spawn_invalidation(self: &Arc<Self>) {
+        let Some(watcher) = &self.watcher else {
+            return;
+        };
+        let mut changes = watcher.subscribe();
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(path) => this.cache.invalidate(&path),
+                    // Not sure what have been missed
+                    Err(RecvError::Lagged(_)) => this.cache.invalidate_all(),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<T>> {
+        self.cache.get(path).map(|(_, listing)| listing)
+    }
+
+    // Must be called before listing the directory, so that changes during listing are not missed.
+    // Returns the generation to insert the listing with.
+    pub fn prepare(&self, path: &Path) -> u64 {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        self.generations
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), generation);
+        if let Some(watcher) = &self.watcher {
+            watcher.watch(path);
+        }
+        generation
+    }
+
+    // Listings prepared before a newer one are dropped, as the newer one would replace them.
+    pub fn insert(&self, path: &Path, generation: u64, listing: Arc<T>) {
+        // Not locked while inserting, as the eviction listener may run within
+        let latest = self.generations.lock().unwrap().get(path) == Some(&generation);
+        if latest {
+            self.cache.insert(path.to_path_buf(), (generation, listing));
+        }
+    }
+}
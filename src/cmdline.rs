@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Debug, Parser)]
 pub struct Cmdline {
@@ -11,4 +11,41 @@ pub struct Cmdline {
         default_value = "/etc/yadex/config.toml"
     )]
     pub config: PathBuf,
+    #[clap(
+        long,
+        help = "check configuration and startup (without serving), reporting all problems"
+    )]
+    pub dry_run: bool,
+    #[clap(
+        long,
+        help = "write PID to this file, for supervisors like runit and OpenRC (removed on exit, unless chrooted)"
+    )]
+    pub pid_file: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[clap(about = "measure listing a directory with current configuration, without serving")]
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    #[clap(help = "directory to list, as in URLs (like /debian/dists/)")]
+    pub dir: String,
+    #[clap(
+        short = 'n',
+        long,
+        help = "times to list the directory",
+        default_value_t = 10
+    )]
+    pub iterations: usize,
+    #[clap(
+        long,
+        help = "instance to use, 0 for the top-level one",
+        default_value_t = 0
+    )]
+    pub instance: usize,
 }
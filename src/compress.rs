@@ -0,0 +1,195 @@
+// Compressing pages for clients accepting it, and caching compressed pages of hot directories, so
+// that repeated requests skip both rendering and compression.
+
+use std::io::Write;
+
+use axum::body::Bytes;
+use flate2::{Compression, write::GzEncoder};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    // As in Content-Encoding
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    // Preferred one in Accept-Encoding, brotli on ties. None for identity. "*" only applies to
+    // codings not listed, so that "br;q=0, *" refuses brotli.
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let items: Vec<(&str, f32)> = accept
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let name = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!name.is_empty()).then_some((name, q))
+            })
+            .collect();
+        let max_of = |matches: &dyn Fn(&str) -> bool| {
+            items
+                .iter()
+                .filter(|(name, _)| matches(name))
+                .map(|(_, q)| *q)
+                .reduce(f32::max)
+        };
+        let wildcard = max_of(&|name| name == "*");
+        let quality = |coding: &str| {
+            max_of(&|name| name.eq_ignore_ascii_case(coding))
+                .or(wildcard)
+                .unwrap_or(0.0)
+        };
+        let (br, gzip) = (quality("br"), quality("gzip"));
+        if br > 0.0 && br >= gzip {
+            Some(Self::Brotli)
+        } else if gzip > 0.0 {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+// Writing into Vec never fails, so errors are not expected below.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            // Quality 11 (the default) is too slow for pages rendered on demand
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    // Compressed so far, flushed so that clients could decompress it without the rest.
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let writer: &mut dyn Write = match self {
+            Self::Brotli(encoder) => encoder.as_mut(),
+            Self::Gzip(encoder) => encoder,
+        };
+        writer
+            .write_all(data)
+            .and_then(|_| writer.flush())
+            .expect("writing into Vec never fails");
+        match self {
+            Self::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+            Self::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Brotli(encoder) => encoder.into_inner(),
+            Self::Gzip(encoder) => encoder.finish().expect("writing into Vec never fails"),
+        }
+    }
+}
+
+pub fn compress(encoding: Encoding, data: &[u8]) -> Bytes {
+    let mut encoder = Encoder::new(encoding);
+    let mut compressed = encoder.push(data);
+    compressed.extend(encoder.finish());
+    compressed.into()
+}
+
+// Compresses pieces of a streamed page as they come. The whole compressed page is given to
+// on_complete when the stream ends without errors.
+pub fn compress_stream<I, E>(
+    pieces: I,
+    encoding: Encoding,
+    mut on_complete: Option<impl FnOnce(Bytes) + Send + 'static>,
+) -> impl Iterator<Item = Result<Bytes, E>> + Send + 'static
+where
+    I: Iterator<Item = Result<String, E>> + Send + 'static,
+{
+    let mut pieces = pieces.fuse();
+    let mut encoder = Some(Encoder::new(encoding));
+    let mut whole = Vec::new();
+    std::iter::from_fn(move || {
+        let compressed = match pieces.next() {
+            Some(Ok(piece)) => encoder.as_mut()?.push(piece.as_bytes()),
+            Some(Err(e)) => {
+                encoder = None;
+                return Some(Err(e));
+            }
+            None => encoder.take()?.finish(),
+        };
+        if on_complete.is_some() {
+            whole.extend_from_slice(&compressed);
+        }
+        if encoder.is_none()
+            && let Some(on_complete) = on_complete.take()
+        {
+            on_complete(std::mem::take(&mut whole).into());
+        }
+        Some(Ok(compressed.into()))
+    })
+}
+
+// Keyed by path, encoding and ETag of the page, so changed pages are never served from here.
+pub struct CompressedCache {
+    cache: moka::sync::Cache<(String, Encoding, u64), Bytes>,
+}
+
+impl CompressedCache {
+    // Capacity is in bytes of compressed pages
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            cache: moka::sync::Cache::builder()
+                .max_capacity(capacity)
+                .weigher(|_, page: &Bytes| page.len().try_into().unwrap_or(u32::MAX).max(1))
+                .build(),
+        }
+    }
+
+    pub fn get(&self, path: &str, encoding: Encoding, etag: u64) -> Option<Bytes> {
+        self.cache.get(&(path.to_string(), encoding, etag))
+    }
+
+    pub fn insert(&self, path: String, encoding: Encoding, etag: u64, page: Bytes) {
+        self.cache.insert((path, encoding, etag), page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    #[test]
+    fn negotiate_prefers_higher_quality() {
+        assert!(Encoding::negotiate(Some("gzip, br")) == Some(Encoding::Brotli));
+        assert!(Encoding::negotiate(Some("br;q=0.5, gzip;q=0.8")) == Some(Encoding::Gzip));
+        assert!(Encoding::negotiate(Some("gzip, deflate")) == Some(Encoding::Gzip));
+        assert!(Encoding::negotiate(Some("identity")).is_none());
+        assert!(Encoding::negotiate(None).is_none());
+    }
+
+    #[test]
+    fn explicit_quality_overrides_wildcard() {
+        assert!(Encoding::negotiate(Some("*")) == Some(Encoding::Brotli));
+        assert!(Encoding::negotiate(Some("br;q=0, *")) == Some(Encoding::Gzip));
+        assert!(Encoding::negotiate(Some("*, br;q=0")) == Some(Encoding::Gzip));
+        assert!(Encoding::negotiate(Some("br;q=0, gzip;q=0, *")).is_none());
+        assert!(Encoding::negotiate(Some("gzip;q=0.5, *;q=0.1")) == Some(Encoding::Gzip));
+    }
+}
@@ -1,4 +1,8 @@
-use std::{net::IpAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -14,32 +18,485 @@ pub enum Security {
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
+    // The first instance, at top level as in configs of a single instance
+    #[serde(flatten)]
+    pub main: InstanceConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    // More instances served by the same process, each with its own listener and root
+    #[serde(default)]
+    pub instance: Vec<InstanceConfig>,
+}
+
+impl Config {
+    pub fn instances(&self) -> impl Iterator<Item = &InstanceConfig> {
+        std::iter::once(&self.main).chain(&self.instance)
+    }
+
+    pub fn into_instances(self) -> Vec<InstanceConfig> {
+        std::iter::once(self.main).chain(self.instance).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InstanceConfig {
     pub network: NetworkConfig,
     pub template: TemplateConfig,
     pub service: ServiceConfig,
+    pub status: Option<StatusConfig>,
+    pub mirrorz: Option<MirrorzConfig>,
+    pub geoip: Option<GeoIPConfig>,
+    pub index: Option<IndexConfig>,
+    pub redirects: Option<RedirectsConfig>,
+    // Requires the "thumbnails" cargo feature
+    pub thumbnails: Option<ThumbnailsConfig>,
+    pub maintenance: Option<MaintenanceConfig>,
+    pub shedding: Option<SheddingConfig>,
+    pub audit: Option<AuditConfig>,
+    pub user_agents: Option<UserAgentsConfig>,
+    pub hotlink: Option<HotlinkConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct NetworkConfig {
     pub address: IpAddr,
     pub port: u16,
+    // Header set by reverse proxy with client's address, like "X-Real-IP".
+    pub real_ip_header: Option<String>,
+    // Log every response with bytes actually sent, at info level with target "yadex::access"
+    #[serde(default = "defaults::bool_false")]
+    pub access_log: bool,
+    #[serde(default = "defaults::bool_false")]
+    pub tcp_nodelay: bool,
+    // Max connections waiting to be accepted
+    #[serde(default = "defaults::backlog")]
+    pub backlog: u32,
+    // Seconds to wait for next request on an HTTP/1 connection, 0 to wait forever
+    #[serde(default)]
+    pub keep_alive_timeout: u64,
+    // Close HTTP/1 connections after this many requests, 0 for unlimited
+    #[serde(default)]
+    pub max_requests_per_connection: u64,
+    // Host names requests must be for, like "mirrors.example.com" or "*.example.com". Empty to allow all.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    // Status and body of responses to requests for other hosts
+    #[serde(default = "defaults::disallowed_host_status")]
+    pub disallowed_host_status: u16,
+    #[serde(default)]
+    pub disallowed_host_body: String,
+}
+
+// Tokio defaults are used when not set.
+#[derive(Serialize, Deserialize, Default)]
+pub struct RuntimeConfig {
+    // Defaults to number of CPUs
+    pub worker_threads: Option<usize>,
+    // For blocking file system operations, defaults to 512
+    pub max_blocking_threads: Option<usize>,
+    // In bytes, defaults to 2 MiB
+    pub thread_stack_size: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TemplateConfig {
+    // Built-in theme used instead of index_file and row_file
+    pub theme: Option<Theme>,
     #[serde(default = "defaults::default_index_file")]
     pub index_file: PathBuf,
+    #[serde(default)]
+    pub help: Vec<HelpConfig>,
+    // Directory of CSS, JS and images served under /_yadex/assets/
+    pub assets_dir: Option<PathBuf>,
+    // Template of a row of entry, registered as partial "row". Required for streaming.
+    pub row_file: Option<PathBuf>,
+    // Template of the page shown in maintenance, a built-in one if not set
+    pub maintenance_file: Option<PathBuf>,
+    // Pages of directories with at least this many entries are streamed row by row
+    #[serde(default = "defaults::stream_threshold")]
+    pub stream_threshold: usize,
+    // Collapse whitespace of rendered pages, outside of <pre>, <textarea>, <script> and <style>
+    #[serde(default = "defaults::bool_false")]
+    pub minify: bool,
+    // IANA name, for showing mtime
+    #[serde(default = "defaults::default_timezone")]
+    pub timezone: String,
+    #[serde(default)]
+    pub size_units: SizeUnits,
+    // Supported locales, like "en" and "zh-CN". The first is the default.
+    #[serde(default)]
+    pub locales: Vec<String>,
+    // strftime-like formats of from_mtimestamp helper, by locale
+    #[serde(default)]
+    pub date_formats: HashMap<String, String>,
+    // Translations of t helper by locale, from strings in templates (like "Name") to translated ones
+    #[serde(default)]
+    pub strings: HashMap<String, HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum Theme {
+    // Sortable table, like etc/index.html
+    #[serde(rename = "classic")]
+    Classic,
+    // Grid of cards
+    #[serde(rename = "modern")]
+    Modern,
+    // Plain lines, like autoindex of nginx
+    #[serde(rename = "dense")]
+    Dense,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum SizeUnits {
+    // KiB, MiB, GiB (1024)
+    #[default]
+    #[serde(rename = "binary")]
+    Binary,
+    // kB, MB, GB (1000)
+    #[serde(rename = "si")]
+    SI,
+}
+
+// Extra block rendered above listings of directories under prefix.
+#[derive(Serialize, Deserialize)]
+pub struct HelpConfig {
+    pub prefix: String,
+    // Relative to config dir
+    pub file: PathBuf,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServiceConfig {
     pub limit: u64,
+    // Like "https://mirrors.example.com", for absolute links instead of deriving from Host
+    pub base_url: Option<String>,
+    // Overrides of limit by path prefix
+    #[serde(default)]
+    pub limits: Vec<LimitConfig>,
+    // Order of directory rows and of file rows, as directories always come first
+    #[serde(default)]
+    pub dir_order: Order,
+    #[serde(default)]
+    pub file_order: Order,
+    // Overrides of orders by path prefix
+    #[serde(default)]
+    pub orders: Vec<OrderConfig>,
     pub root: PathBuf,
     pub security: Security,
     #[serde(default = "defaults::bool_true")]
     pub template_index: bool,
     #[serde(default = "defaults::bool_false")]
     pub json_api: bool,
+    // Show recursive sizes of directories, computed in background
+    #[serde(default = "defaults::bool_false")]
+    pub dir_size: bool,
+    // In seconds
+    #[serde(default = "defaults::dir_size_ttl")]
+    pub dir_size_ttl: u64,
+    // Max number of directories cached
+    #[serde(default = "defaults::dir_size_capacity")]
+    pub dir_size_capacity: u64,
+    // Max number of directories being walked at the same time
+    #[serde(default = "defaults::dir_size_concurrency")]
+    pub dir_size_concurrency: usize,
+    // Max number of entries being stat-ed at the same time when listing
+    #[serde(default = "defaults::stat_concurrency")]
+    pub stat_concurrency: usize,
+    // Directories with at least this many entries are listed without stat, 0 to disable
+    #[serde(default)]
+    pub fast_listing_threshold: usize,
+    // Max bytes taken by a listing being built, beyond which it's truncated. 0 to disable.
+    #[serde(default = "defaults::listing_memory_limit")]
+    pub listing_memory_limit: u64,
+    // Include mode, uid/gid and owner names in entries
+    #[serde(default = "defaults::bool_false")]
+    pub show_owner: bool,
+    // Seconds to cache entries of directories, 0 to disable
+    #[serde(default)]
+    pub cache_ttl: u64,
+    // Max total number of entries of cached directories
+    #[serde(default = "defaults::cache_capacity")]
+    pub cache_capacity: u64,
+    // Invalidate cache of changed directories with inotify
+    #[serde(default = "defaults::bool_true")]
+    pub cache_watch: bool,
+    // Push changes of directories as server-sent events, with inotify
+    #[serde(default = "defaults::bool_false")]
+    pub live_updates: bool,
+    // Render Markdown files inside the template for ?view=rendered
+    #[serde(default = "defaults::bool_false")]
+    pub markdown_preview: bool,
+    // Show text files highlighted inside the template for ?view=text
+    #[serde(default = "defaults::bool_false")]
+    pub text_preview: bool,
+    // Max bytes of files rendered for ?view, larger ones are redirected to as-is
+    #[serde(default = "defaults::preview_max_size")]
+    pub preview_max_size: u64,
+    // Compress pages with brotli or gzip for clients accepting them
+    #[serde(default = "defaults::bool_false")]
+    pub compress: bool,
+    // Max bytes of compressed pages cached, 0 to disable caching
+    #[serde(default = "defaults::compressed_cache_capacity")]
+    pub compressed_cache_capacity: u64,
+    // Hide entries on other filesystems than root's (like mount points), and refuse paths into them
+    #[serde(default = "defaults::bool_false")]
+    pub one_filesystem: bool,
+}
+
+impl ServiceConfig {
+    // Where a path read after chroot (like the status file) is, as seen before it, so that it
+    // could be checked at startup.
+    pub fn before_chroot(&self, path: &Path) -> PathBuf {
+        match self.security {
+            Security::Chroot => self.root.join(path.strip_prefix("/").unwrap_or(path)),
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LimitConfig {
+    pub prefix: String,
+    // 0 for unlimited, like limit
+    pub limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum Order {
+    #[default]
+    #[serde(rename = "name")]
+    Name,
+    // By mtime, then by name
+    #[serde(rename = "newest")]
+    Newest,
+    #[serde(rename = "oldest")]
+    Oldest,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderConfig {
+    pub prefix: String,
+    // Unset ones are dir_order and file_order
+    pub dirs: Option<Order>,
+    pub files: Option<Order>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum StatusFormat {
+    // JSON array of job status, as returned by tunasync manager's /jobs.
+    #[default]
+    #[serde(rename = "tunasync")]
+    Tunasync,
+    // JSON object mapping path prefixes to status.
+    #[serde(rename = "prefix")]
+    Prefix,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusConfig {
+    // Path to a file, or an http:// URL.
+    pub source: String,
+    #[serde(default)]
+    pub format: StatusFormat,
+    // In seconds
+    #[serde(default = "defaults::status_refresh_interval")]
+    pub refresh_interval: u64,
+    // In seconds, for fetching from an http:// URL
+    #[serde(default = "defaults::status_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MirrorzConfig {
+    // Passed through as-is into "site" and "info" of mirrorz.json.
+    pub site: serde_json::Value,
+    #[serde(default)]
+    pub info: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub mirrors: Vec<MirrorzMirror>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MirrorzMirror {
+    pub cname: String,
+    #[serde(default)]
+    pub desc: String,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub help: String,
+    #[serde(default)]
+    pub upstream: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GeoIPConfig {
+    // MaxMind DB files, like GeoLite2-Country.mmdb and GeoLite2-ASN.mmdb
+    pub country_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+    #[serde(default)]
+    pub rules: Vec<GeoIPRule>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GeoIPRule {
+    pub prefixes: Vec<String>,
+    // ISO 3166-1 alpha-2 codes
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    // Part of path after the prefix is appended to it.
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IndexConfig {
+    // Created if not exists
+    pub database: PathBuf,
+    // Seconds between scans
+    #[serde(default = "defaults::index_interval")]
+    pub interval: u64,
+    // Directories with at least this many entries are listed from the index
+    #[serde(default = "defaults::index_threshold")]
+    pub threshold: u64,
+    // Max number of searches (and other queries walking the index) running at the same time
+    #[serde(default = "defaults::index_query_concurrency")]
+    pub query_concurrency: usize,
+    // Max number of entries a search looks at, 0 for unlimited
+    #[serde(default = "defaults::index_search_max_scan")]
+    pub search_max_scan: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedirectsConfig {
+    // Lines of "path target"
+    pub file: PathBuf,
+    // 301, 302, 307 or 308
+    #[serde(default = "defaults::redirects_status")]
+    pub status: u16,
+    // Seconds between checking whether the file changed
+    #[serde(default = "defaults::redirects_reload_interval")]
+    pub reload_interval: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ThumbnailsConfig {
+    // Created if not exists
+    pub cache_dir: PathBuf,
+    // In bytes, least recently used thumbnails are removed beyond it. 0 for unlimited.
+    #[serde(default = "defaults::thumbnail_max_cache_size")]
+    pub max_cache_size: u64,
+    // Allowed values of ?thumb, in pixels
+    #[serde(default = "defaults::thumbnail_sizes")]
+    pub sizes: Vec<u32>,
+    // Larger images are redirected to as-is
+    #[serde(default = "defaults::thumbnail_max_source_size")]
+    pub max_source_size: u64,
+    // Max number of images being decoded at the same time
+    #[serde(default = "defaults::thumbnail_concurrency")]
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    // All requests are answered with 503 while this file exists
+    pub flag_file: Option<PathBuf>,
+    // Requests under these are answered with 503, while the rest stays up
+    #[serde(default)]
+    pub prefixes: Vec<MaintenancePrefixConfig>,
+    // Seconds, sent as Retry-After
+    #[serde(default = "defaults::maintenance_retry_after")]
+    pub retry_after: u64,
+    // Seconds between checking whether flag files exist
+    #[serde(default = "defaults::maintenance_check_interval")]
+    pub check_interval: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MaintenancePrefixConfig {
+    pub prefix: String,
+    // Only while this file exists if set, always otherwise
+    pub flag_file: Option<PathBuf>,
+    // Shown on the page, like "Being re-synced from scratch"
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SheddingConfig {
+    // Milliseconds per I/O operation of listings (moving average), above which storage is saturated
+    #[serde(default = "defaults::shedding_latency_threshold")]
+    pub latency_threshold: u64,
+    // Listings read from storage at the same time while saturated
+    #[serde(default = "defaults::shedding_concurrency")]
+    pub concurrency: usize,
+    // Listings waiting while saturated, more are rejected with 503
+    #[serde(default = "defaults::shedding_max_queued")]
+    pub max_queued: usize,
+    // Seconds, sent as Retry-After of rejected requests
+    #[serde(default = "defaults::shedding_retry_after")]
+    pub retry_after: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditConfig {
+    // JSON lines are appended to it
+    pub file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserAgentsConfig {
+    // The first matching rule applies
+    #[serde(default)]
+    pub rules: Vec<UserAgentRule>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserAgentRule {
+    // Regex matched against User-Agent, empty if not sent
+    pub pattern: String,
+    pub action: UserAgentAction,
+    // For "throttle": requests allowed from each client in interval seconds
+    #[serde(default = "defaults::throttle_requests")]
+    pub requests: u32,
+    #[serde(default = "defaults::throttle_interval")]
+    pub interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum UserAgentAction {
+    // 403
+    #[serde(rename = "block")]
+    Block,
+    // 429 once exceeding requests in interval
+    #[serde(rename = "throttle")]
+    Throttle,
+    // Listings as plain text, one name per line, without rendering templates
+    #[serde(rename = "plain")]
+    Plain,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HotlinkConfig {
+    // The longest matching prefix applies
+    #[serde(default)]
+    pub rules: Vec<HotlinkRule>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HotlinkRule {
+    pub prefix: String,
+    // Hosts of referers allowed besides the requested host, like "example.com" or "*.example.com"
+    #[serde(default)]
+    pub allowed_referers: Vec<String>,
+    // Whether requests without Referer are allowed
+    #[serde(default = "defaults::bool_true")]
+    pub allow_empty: bool,
+    // Extensions of paths never checked, like "sig"
+    #[serde(default)]
+    pub exempt_extensions: Vec<String>,
+    // Denied requests are redirected here (302) if set, or answered with 403
+    pub redirect: Option<String>,
 }
 
 mod defaults {
@@ -51,7 +508,136 @@ mod defaults {
         false
     }
 
+    // Misdirected Request
+    pub fn disallowed_host_status() -> u16 {
+        421
+    }
+
+    pub fn backlog() -> u32 {
+        1024
+    }
+
+    pub fn cache_capacity() -> u64 {
+        1_000_000
+    }
+
+    pub fn compressed_cache_capacity() -> u64 {
+        64 << 20
+    }
+
+    pub fn dir_size_ttl() -> u64 {
+        3600
+    }
+
+    pub fn dir_size_capacity() -> u64 {
+        100_000
+    }
+
+    pub fn dir_size_concurrency() -> usize {
+        2
+    }
+
+    pub fn stream_threshold() -> usize {
+        5000
+    }
+
+    pub fn index_interval() -> u64 {
+        3600
+    }
+
+    pub fn index_threshold() -> u64 {
+        10_000
+    }
+
+    pub fn index_query_concurrency() -> usize {
+        2
+    }
+
+    pub fn index_search_max_scan() -> u64 {
+        1_000_000
+    }
+
+    pub fn listing_memory_limit() -> u64 {
+        256 << 20
+    }
+
+    pub fn throttle_requests() -> u32 {
+        10
+    }
+
+    pub fn throttle_interval() -> u64 {
+        60
+    }
+
+    pub fn shedding_latency_threshold() -> u64 {
+        50
+    }
+
+    pub fn shedding_concurrency() -> usize {
+        2
+    }
+
+    pub fn shedding_max_queued() -> usize {
+        32
+    }
+
+    pub fn shedding_retry_after() -> u64 {
+        10
+    }
+
+    pub fn maintenance_retry_after() -> u64 {
+        300
+    }
+
+    pub fn maintenance_check_interval() -> u64 {
+        5
+    }
+
+    pub fn preview_max_size() -> u64 {
+        1 << 20
+    }
+
+    pub fn stat_concurrency() -> usize {
+        16
+    }
+
+    pub fn status_refresh_interval() -> u64 {
+        60
+    }
+
+    pub fn status_timeout() -> u64 {
+        10
+    }
+
+    pub fn default_timezone() -> String {
+        "UTC".to_string()
+    }
+
     pub fn default_index_file() -> std::path::PathBuf {
         "index.html".to_string().into()
     }
+
+    pub fn redirects_status() -> u16 {
+        301
+    }
+
+    pub fn redirects_reload_interval() -> u64 {
+        60
+    }
+
+    pub fn thumbnail_sizes() -> Vec<u32> {
+        vec![128, 256, 512]
+    }
+
+    pub fn thumbnail_max_source_size() -> u64 {
+        64 << 20
+    }
+
+    pub fn thumbnail_concurrency() -> usize {
+        2
+    }
+
+    pub fn thumbnail_max_cache_size() -> u64 {
+        1024 * 1024 * 1024
+    }
 }
@@ -0,0 +1,91 @@
+// Recursive directory sizes, computed in background and cached.
+
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Semaphore;
+
+pub struct DirSizeCache {
+    cache: moka::sync::Cache<PathBuf, u64>,
+    // Directories being walked, to avoid walking the same one twice
+    pending: Mutex<HashSet<PathBuf>>,
+    // Limits directories being walked (and tasks spawned) at the same time
+    semaphore: Arc<Semaphore>,
+    // Relative paths are resolved against it
+    root: PathBuf,
+    // Of root, with one_filesystem
+    device: Option<u64>,
+}
+
+// Symlinks are not followed, and errors are ignored. With device, other filesystems are skipped.
+// Directories are kept in a stack instead of recursing, as deep trees could overflow the stack
+// of blocking threads.
+fn walk(path: &Path, device: Option<u64>) -> u64 {
+    let mut size = 0;
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(dir) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in dir.filter_map(Result::ok) {
+            match entry.metadata() {
+                Ok(meta) if device.is_some_and(|device| meta.dev() != device) => {}
+                Ok(meta) if meta.is_dir() => pending.push(entry.path()),
+                Ok(meta) => size += meta.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+impl DirSizeCache {
+    pub fn new(
+        ttl: Duration,
+        capacity: u64,
+        concurrency: usize,
+        root: PathBuf,
+        device: Option<u64>,
+    ) -> Self {
+        Self {
+            cache: moka::sync::Cache::builder()
+                .time_to_live(ttl)
+                .max_capacity(capacity)
+                .build(),
+            pending: Mutex::new(HashSet::new()),
+            semaphore: Arc::new(Semaphore::new(concurrency.max(1))),
+            root,
+            device,
+        }
+    }
+
+    // Returns cached size, or starts computing it and returns None. When all walkers are busy,
+    // nothing is queued, and later requests for the directory try again.
+    pub fn get(self: &Arc<Self>, path: &Path) -> Option<u64> {
+        if let Some(size) = self.cache.get(path) {
+            return Some(size);
+        }
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        if !self.pending.lock().unwrap().insert(path.to_path_buf()) {
+            return None;
+        }
+        let this = self.clone();
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let (walk_path, device) = (this.root.join(&path), this.device);
+            match tokio::task::spawn_blocking(move || walk(&walk_path, device)).await {
+                Ok(size) => this.cache.insert(path.clone(), size),
+                Err(e) => tracing::error!("failed to compute size of {path:?}: {e}"),
+            }
+            this.pending.lock().unwrap().remove(&path);
+        });
+        None
+    }
+}
@@ -0,0 +1,158 @@
+// Startup checks for --dry-run: everything but serving, with all problems reported at once.
+
+use std::{error::Error, fmt::Display, path::Path};
+
+use yadex::{
+    config::{self, Config, InstanceConfig},
+    geoip::GeoRedirect,
+    redirects::Redirects,
+    serve,
+    server::{Settings, Template, fs_root},
+    status::StatusStore,
+    user_agent::UserAgentRules,
+};
+
+use crate::{cmdline::Cmdline, landlock::check_landlock};
+
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn check<T, E: Error>(&mut self, context: impl Display, result: Result<T, E>) -> Option<T> {
+        result
+            .map_err(|e| {
+                let chain = std::iter::successors(Some(&e as &dyn Error), |e| (*e).source());
+                self.push(context, describe(chain.map(ToString::to_string)));
+            })
+            .ok()
+    }
+
+    fn push(&mut self, context: impl Display, message: impl Display) {
+        self.0.push(format!("{context}: {message}"));
+    }
+}
+
+// Messages of an error and its sources, skipping sources already included by the error.
+fn describe(mut chain: impl Iterator<Item = String>) -> String {
+    let mut message = chain.next().unwrap_or_default();
+    for source in chain {
+        if !message.contains(&source) {
+            message.push_str(": ");
+            message.push_str(&source);
+        }
+    }
+    message
+}
+
+// Must be called within tokio runtime, for the bind test.
+pub fn dry_run(cmdline: &Cmdline, config: Config) -> Vec<String> {
+    let mut problems = Problems::default();
+    if let Err(e) = crate::check_security(&config) {
+        problems.push("security", e);
+    }
+    if let Some(pid_file) = &cmdline.pid_file {
+        check_parent(&mut problems, "cmdline", "pid file", pid_file);
+    }
+    if config.main.service.security == config::Security::Landlock {
+        for e in check_landlock(cmdline, &config) {
+            problems.push("landlock", describe(e.chain().map(ToString::to_string)));
+        }
+    }
+    for (i, instance) in config.into_instances().into_iter().enumerate() {
+        let context = format!(
+            "instance {i} ({}:{})",
+            instance.network.address, instance.network.port
+        );
+        check_instance(&mut problems, &context, cmdline, instance);
+    }
+    problems.0
+}
+
+fn check_instance(
+    problems: &mut Problems,
+    context: &str,
+    cmdline: &Cmdline,
+    config: InstanceConfig,
+) {
+    let root = &config.service.root;
+    match std::fs::metadata(root) {
+        Ok(metadata) if !metadata.is_dir() => {
+            problems.push(
+                context,
+                format!("root {} is not a directory", root.display()),
+            );
+        }
+        result => {
+            problems.check(format_args!("{context}: root {}", root.display()), result);
+        }
+    }
+    problems.check(context, fs_root(&config.service));
+    problems.check(context, Settings::parse(&config.service, &config.network));
+    if config.service.template_index {
+        problems.check(
+            format_args!("{context}: template"),
+            Template::from_config(&cmdline.config, config.template),
+        );
+    }
+    if let Some(status) = config.status {
+        problems.check(
+            format_args!("{context}: status"),
+            StatusStore::from_config(status, &config.service),
+        );
+    }
+    if let Some(geoip) = config.geoip {
+        problems.check(
+            format_args!("{context}: geoip"),
+            GeoRedirect::from_config(geoip),
+        );
+    }
+    if let Some(redirects) = config.redirects {
+        problems.check(
+            format_args!("{context}: redirects"),
+            Redirects::from_config(redirects, &config.service),
+        );
+    }
+    if let Some(user_agents) = config.user_agents {
+        problems.check(
+            format_args!("{context}: user_agents"),
+            UserAgentRules::from_config(user_agents),
+        );
+    }
+    // Files created on startup, which a dry run leaves alone
+    if let Some(index) = &config.index {
+        check_parent(problems, context, "index database", &index.database);
+    }
+    if let Some(audit) = &config.audit {
+        check_parent(problems, context, "audit log", &audit.file);
+    }
+    if config.thumbnails.is_some() {
+        if cfg!(not(feature = "thumbnails")) {
+            problems.push(
+                context,
+                "[thumbnails] requires yadex built with the \"thumbnails\" feature",
+            );
+        } else if config.service.security == config::Security::Chroot {
+            problems.push(
+                context,
+                "thumbnails are not supported with chroot, use landlock",
+            );
+        }
+    }
+    problems.check(
+        format_args!("{context}: bind"),
+        serve::bind(&config.network),
+    );
+}
+
+fn check_parent(problems: &mut Problems, context: &str, what: &str, path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        problems.push(
+            context,
+            format!("directory of {what} {} does not exist", path.display()),
+        );
+    }
+}
@@ -0,0 +1,82 @@
+// Redirecting requests to partner mirrors by client's country or ASN.
+
+use std::{net::IpAddr, path::PathBuf};
+
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{GeoIPConfig, GeoIPRule};
+
+#[derive(Debug, Snafu)]
+#[snafu(display("failed to load GeoIP database from {path:?}: {source}"))]
+pub struct GeoIPLoadError {
+    path: PathBuf,
+    source: MaxMindDBError,
+}
+
+pub struct GeoRedirect {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    rules: Vec<GeoIPRule>,
+}
+
+fn open(path: Option<PathBuf>) -> Result<Option<Reader<Vec<u8>>>, GeoIPLoadError> {
+    path.map(|path| Reader::open_readfile(&path).context(GeoIPLoadSnafu { path }))
+        .transpose()
+}
+
+impl GeoRedirect {
+    pub fn from_config(config: GeoIPConfig) -> Result<Self, GeoIPLoadError> {
+        Ok(Self {
+            country: open(config.country_database)?,
+            asn: open(config.asn_database)?,
+            rules: config.rules,
+        })
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<&str> {
+        self.country
+            .as_ref()?
+            .lookup::<geoip2::Country>(ip)
+            .ok()?
+            .country?
+            .iso_code
+    }
+
+    fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.asn
+            .as_ref()?
+            .lookup::<geoip2::Asn>(ip)
+            .ok()?
+            .autonomous_system_number
+    }
+
+    // Returns where to redirect a request for path (and query), if any rule matches.
+    pub fn redirect(&self, ip: IpAddr, path: &str, query: Option<&str>) -> Option<String> {
+        let mut rules = self
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let prefix = rule
+                    .prefixes
+                    .iter()
+                    .find(|p| path.starts_with(p.as_str()))?;
+                Some((rule, prefix))
+            })
+            .peekable();
+        // Avoid database lookups for most requests
+        rules.peek()?;
+        let country = self.country(ip);
+        let asn = self.asn(ip);
+        let (rule, prefix) = rules.find(|(rule, _)| {
+            country.is_some_and(|c| rule.countries.iter().any(|rc| rc.eq_ignore_ascii_case(c)))
+                || asn.is_some_and(|a| rule.asns.contains(&a))
+        })?;
+        let mut target = format!("{}{}", rule.target, &path[prefix.len()..]);
+        if let Some(query) = query {
+            target.push('?');
+            target.push_str(query);
+        }
+        Some(target)
+    }
+}
@@ -0,0 +1,86 @@
+// Hotlink protection by Referer, for sections of the mirror embedded by third-party sites.
+
+use std::path::Path;
+
+use axum::http::Uri;
+
+use crate::{config::HotlinkConfig, server::host_matches, status::normalize_prefix};
+
+struct Rule {
+    // Normalized, like "/debian/"
+    prefix: String,
+    // Lowercase
+    allowed_referers: Vec<String>,
+    allow_empty: bool,
+    // Lowercase, without "."
+    exempt_extensions: Vec<String>,
+    redirect: Option<String>,
+}
+
+pub enum Verdict<'a> {
+    Allow,
+    // Redirect target if set, 403 otherwise
+    Deny(Option<&'a str>),
+}
+
+pub struct Hotlink {
+    // Longest first
+    rules: Vec<Rule>,
+}
+
+impl Hotlink {
+    pub fn from_config(config: HotlinkConfig) -> Self {
+        let lowercase = |v: Vec<String>| v.into_iter().map(|s| s.to_ascii_lowercase()).collect();
+        let mut rules: Vec<_> = config
+            .rules
+            .into_iter()
+            .map(|rule| Rule {
+                prefix: normalize_prefix(&rule.prefix),
+                allowed_referers: lowercase(rule.allowed_referers),
+                allow_empty: rule.allow_empty,
+                exempt_extensions: lowercase(
+                    rule.exempt_extensions
+                        .into_iter()
+                        .map(|ext| ext.trim_start_matches('.').to_string())
+                        .collect(),
+                ),
+                redirect: rule.redirect,
+            })
+            .collect();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+        Self { rules }
+    }
+
+    // Decoded path of the request, and host it's for. Referers from the same host are allowed.
+    // None if no rule applies to the path.
+    pub fn check(&self, path: &str, host: &str, referer: Option<&str>) -> Option<Verdict<'_>> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| path.starts_with(&rule.prefix))?;
+        let exempt = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                rule.exempt_extensions
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(ext))
+            });
+        let allowed = match referer.filter(|r| !r.is_empty()) {
+            _ if exempt => true,
+            None => rule.allow_empty,
+            Some(referer) => referer
+                .parse::<Uri>()
+                .ok()
+                .and_then(|uri| uri.host().map(str::to_string))
+                .is_some_and(|referer_host| {
+                    referer_host.eq_ignore_ascii_case(host)
+                        || host_matches(&rule.allowed_referers, &referer_host)
+                }),
+        };
+        Some(match allowed {
+            true => Verdict::Allow,
+            false => Verdict::Deny(rule.redirect.as_deref()),
+        })
+    }
+}
@@ -0,0 +1,577 @@
+// Persistent index of the whole tree, for directories too large to list on each request.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    ops::Bound,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use redb::{Database, Durability, ReadableTable, ReadableTableMetadata, TableDefinition};
+use snafu::{ResultExt, Snafu};
+use tokio::{sync::Semaphore, task::JoinError};
+
+use crate::{config::IndexConfig, server::to_limit};
+
+// (parent, name) -> (size, mtime, is_dir, is_symlink). Parents are like "debian/dists", and "" for root.
+const ENTRIES: TableDefinition<(&str, &str), (u64, i64, bool, bool)> =
+    TableDefinition::new("entries");
+// Directory -> (mtime in nanoseconds, number of entries), as of last listing
+const DIRS: TableDefinition<&str, (i64, u64)> = TableDefinition::new("dirs");
+// Files of ENTRIES ordered by mtime: (mtime, parent, name) -> (size, is_symlink)
+const RECENT: TableDefinition<(i64, &str, &str), (u64, bool)> = TableDefinition::new("recent");
+
+// Errors of redb are of various types, and large
+type DbError = Box<dyn std::error::Error + Send + Sync>;
+
+// Listed directories between durable commits, so that freed pages could be reused during long scans
+const DIRS_PER_COMMIT: u64 = 1000;
+
+#[derive(Debug, Snafu)]
+pub enum IndexError {
+    #[snafu(display("failed to open index database {path:?}: {source}"))]
+    Open {
+        path: PathBuf,
+        source: redb::DatabaseError,
+    },
+    #[snafu(display("failed to initialize index database: {source}"))]
+    Init { source: DbError },
+}
+
+pub struct IndexedEntry {
+    pub name: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+}
+
+pub struct FileIndex {
+    db: Database,
+    // Relative paths of directories are resolved against it
+    root: PathBuf,
+    // Of root, with one_filesystem
+    device: Option<u64>,
+    // Directories with fewer entries are listed with readdir as usual
+    threshold: u64,
+    interval: Duration,
+    // Limits queries walking the database at the same time
+    queries: Semaphore,
+    // Max number of entries a search looks at
+    search_max_scan: usize,
+}
+
+// "./debian/dists" -> "debian/dists", "." -> ""
+fn key(path: &Path) -> Option<&str> {
+    path.strip_prefix(".").ok()?.to_str()
+}
+
+fn child(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+fn fs_path(root: &Path, dir: &str) -> PathBuf {
+    root.join(dir)
+}
+
+fn mtime_ns(meta: &fs::Metadata) -> i64 {
+    meta.mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(meta.mtime_nsec())
+}
+
+// Like what listings show: hidden entries (and those on other filesystems than device) are skipped, and
+// symlinks are followed.
+fn read_dir(root: &Path, dir: &str, device: Option<u64>) -> Vec<IndexedEntry> {
+    let Ok(entries) = fs::read_dir(fs_path(root, dir)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                return None;
+            }
+            let is_symlink = entry.file_type().ok()?.is_symlink();
+            // Like listings, by what symlinks point to, or by dangling symlinks themselves
+            let meta = fs::metadata(entry.path())
+                .or_else(|_| entry.metadata())
+                .ok()?;
+            if device.is_some_and(|device| meta.dev() != device) {
+                return None;
+            }
+            Some(IndexedEntry {
+                size: if meta.is_dir() { 0 } else { meta.size() },
+                mtime: meta.mtime(),
+                is_dir: meta.is_dir(),
+                is_symlink,
+                name,
+            })
+        })
+        .collect()
+}
+
+fn indexed_entry(
+    name: &str,
+    (size, mtime, is_dir, is_symlink): (u64, i64, bool, bool),
+) -> IndexedEntry {
+    IndexedEntry {
+        name: name.to_string(),
+        size,
+        mtime,
+        is_dir,
+        is_symlink,
+    }
+}
+
+// Whether parent is dir or under it
+fn in_subtree(dir: &str, parent: &str) -> bool {
+    dir.is_empty()
+        || parent
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Removes files of ENTRIES in range from RECENT.
+fn unrecord_files<'a>(
+    table: &redb::Table<(&'static str, &'static str), (u64, i64, bool, bool)>,
+    recent: &mut redb::Table<(i64, &'static str, &'static str), (u64, bool)>,
+    range: impl std::ops::RangeBounds<(&'a str, &'a str)> + 'a,
+) -> Result<(), DbError> {
+    for item in table.range::<(&str, &str)>(range)? {
+        let (k, v) = item?;
+        let (parent, name) = k.value();
+        let (_, mtime, is_dir, _) = v.value();
+        if !is_dir {
+            recent.remove((mtime, parent, name))?;
+        }
+    }
+    Ok(())
+}
+
+type Range<'a> = redb::Range<'a, (&'static str, &'static str), (u64, i64, bool, bool)>;
+
+// Entries of a directory, then of its descendants like "{dir}/...".
+fn subtree<'a>(
+    table: &'a redb::ReadOnlyTable<(&'static str, &'static str), (u64, i64, bool, bool)>,
+    dir: &str,
+) -> Result<impl Iterator<Item = <Range<'a> as Iterator>::Item>, DbError> {
+    if dir.is_empty() {
+        return Ok(vec![table.range::<(&str, &str)>(..)?].into_iter().flatten());
+    }
+    let (start, end) = (format!("{dir}/"), format!("{dir}0"));
+    Ok(vec![
+        table.range((dir, "")..=(dir, "\u{10ffff}"))?,
+        table.range((start.as_str(), "")..(end.as_str(), ""))?,
+    ]
+    .into_iter()
+    .flatten())
+}
+
+impl FileIndex {
+    // Root is where files are, as seen after chroot.
+    pub fn from_config(
+        config: IndexConfig,
+        root: PathBuf,
+        device: Option<u64>,
+    ) -> Result<Self, IndexError> {
+        let db = Database::create(&config.database).context(OpenSnafu {
+            path: &config.database,
+        })?;
+        let init = || -> Result<(), DbError> {
+            let txn = db.begin_write()?;
+            {
+                let table = txn.open_table(ENTRIES)?;
+                txn.open_table(DIRS)?;
+                // Databases created before RECENT existed
+                let mut recent = txn.open_table(RECENT)?;
+                if recent.is_empty()? && !table.is_empty()? {
+                    for item in table.range::<(&str, &str)>(..)? {
+                        let (k, v) = item?;
+                        let (parent, name) = k.value();
+                        let (size, mtime, is_dir, is_symlink) = v.value();
+                        if !is_dir {
+                            recent.insert((mtime, parent, name), (size, is_symlink))?;
+                        }
+                    }
+                }
+            }
+            Ok(txn.commit()?)
+        };
+        init().context(InitSnafu)?;
+        Ok(Self {
+            db,
+            root,
+            device,
+            threshold: config.threshold,
+            interval: Duration::from_secs(config.interval),
+            queries: Semaphore::new(config.query_concurrency.max(1)),
+            search_max_scan: to_limit(config.search_max_scan),
+        })
+    }
+
+    // Must be called after chroot, if any.
+    pub fn spawn_scan(self: &Arc<Self>) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let scanner = this.clone();
+                let started = Instant::now();
+                match tokio::task::spawn_blocking(move || scanner.scan()).await {
+                    Ok(Ok(listed)) => tracing::debug!(
+                        "index updated, {listed} changed directories listed in {:?}",
+                        started.elapsed()
+                    ),
+                    Ok(Err(e)) => tracing::error!("failed to update index: {e}"),
+                    Err(e) => tracing::error!("failed to update index: {e}"),
+                }
+                tokio::time::sleep(this.interval).await;
+            }
+        });
+    }
+
+    // Only directories whose mtime changed since last scan are listed again, and others are walked
+    // through with subdirectories recorded. Note that files changed in place are not noticed this way.
+    fn scan(&self) -> Result<u64, DbError> {
+        let mut listed = 0;
+        let mut pending = vec![String::new()];
+        while let Some(dir) = pending.pop() {
+            // Removed directories are cleaned up when their parents are listed again
+            let Ok(meta) = fs::metadata(fs_path(&self.root, &dir)) else {
+                continue;
+            };
+            let mtime = mtime_ns(&meta);
+            let recorded = self
+                .db
+                .begin_read()?
+                .open_table(DIRS)?
+                .get(dir.as_str())?
+                .map(|v| v.value().0);
+            let subdirs = if recorded == Some(mtime) {
+                self.subdirs(&dir)?
+            } else {
+                listed += 1;
+                let entries = read_dir(&self.root, &dir, self.device);
+                self.update(&dir, mtime, entries, listed % DIRS_PER_COMMIT == 0)?
+            };
+            pending.extend(subdirs.into_iter().map(|name| child(&dir, &name)));
+        }
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(Durability::Immediate);
+        txn.commit()?;
+        Ok(listed)
+    }
+
+    // Names of subdirectories (not symlinks to directories) recorded of a directory.
+    fn subdirs(&self, dir: &str) -> Result<HashSet<String>, DbError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+        let mut subdirs = HashSet::new();
+        for item in table.range((dir, "")..)? {
+            let (k, v) = item?;
+            let (parent, name) = k.value();
+            if parent != dir {
+                break;
+            }
+            let (_, _, is_dir, is_symlink) = v.value();
+            if is_dir && !is_symlink {
+                subdirs.insert(name.to_string());
+            }
+        }
+        Ok(subdirs)
+    }
+
+    // Replaces recorded entries of a directory, dropping whatever recorded under subdirectories gone.
+    // Returns names of subdirectories.
+    fn update(
+        &self,
+        dir: &str,
+        mtime: i64,
+        entries: Vec<IndexedEntry>,
+        durable: bool,
+    ) -> Result<HashSet<String>, DbError> {
+        let old = self.subdirs(dir)?;
+        let new: HashSet<String> = entries
+            .iter()
+            .filter(|e| e.is_dir && !e.is_symlink)
+            .map(|e| e.name.clone())
+            .collect();
+        let mut txn = self.db.begin_write()?;
+        if !durable {
+            txn.set_durability(Durability::None);
+        }
+        {
+            let mut table = txn.open_table(ENTRIES)?;
+            let mut dirs = txn.open_table(DIRS)?;
+            let mut recent = txn.open_table(RECENT)?;
+            unrecord_files(&table, &mut recent, (dir, "")..=(dir, "\u{10ffff}"))?;
+            table.retain_in((dir, "")..=(dir, "\u{10ffff}"), |_, _| false)?;
+            for entry in &entries {
+                table.insert(
+                    (dir, entry.name.as_str()),
+                    (entry.size, entry.mtime, entry.is_dir, entry.is_symlink),
+                )?;
+                if !entry.is_dir {
+                    recent.insert(
+                        (entry.mtime, dir, entry.name.as_str()),
+                        (entry.size, entry.is_symlink),
+                    )?;
+                }
+            }
+            dirs.insert(dir, (mtime, entries.len() as u64))?;
+            for gone in old.difference(&new) {
+                let gone = child(dir, gone);
+                // Descendants are like "{gone}/...", which sort between "{gone}/" and "{gone}0"
+                let (start, end) = (format!("{gone}/"), format!("{gone}0"));
+                unrecord_files(
+                    &table,
+                    &mut recent,
+                    (gone.as_str(), "")..=(gone.as_str(), "\u{10ffff}"),
+                )?;
+                unrecord_files(
+                    &table,
+                    &mut recent,
+                    (start.as_str(), "")..(end.as_str(), ""),
+                )?;
+                table.retain_in(
+                    (gone.as_str(), "")..=(gone.as_str(), "\u{10ffff}"),
+                    |_, _| false,
+                )?;
+                table.retain_in((start.as_str(), "")..(end.as_str(), ""), |_, _| false)?;
+                dirs.remove(gone.as_str())?;
+                dirs.retain_in(start.as_str()..end.as_str(), |_, _| false)?;
+            }
+        }
+        txn.commit()?;
+        Ok(new)
+    }
+
+    // Number of entries of a large directory, if the index is up to date with it.
+    fn fresh_count(
+        &self,
+        txn: &redb::ReadTransaction,
+        dir: &str,
+        path: &Path,
+    ) -> Result<Option<u64>, DbError> {
+        let Ok(meta) = fs::metadata(self.root.join(path)) else {
+            return Ok(None);
+        };
+        let Some((recorded, count)) = txn.open_table(DIRS)?.get(dir)?.map(|v| v.value()) else {
+            return Ok(None);
+        };
+        Ok((recorded == mtime_ns(&meta) && count >= self.threshold).then_some(count))
+    }
+
+    // Entries of a large directory, if the index is up to date with it, and the number of all entries
+    // if they are truncated by limit or memory budget (in bytes, roughly counted by names). Blocking.
+    pub fn list(
+        &self,
+        path: &Path,
+        limit: usize,
+        budget: usize,
+    ) -> Option<(Vec<IndexedEntry>, Option<u64>)> {
+        let dir = key(path)?;
+        let result = (|| -> Result<_, DbError> {
+            let txn = self.db.begin_read()?;
+            let Some(count) = self.fresh_count(&txn, dir, path)? else {
+                return Ok(None);
+            };
+            let table = txn.open_table(ENTRIES)?;
+            let mut entries = Vec::new();
+            let mut used = 0;
+            for item in table.range((dir, "")..)? {
+                let (k, v) = item?;
+                let (parent, name) = k.value();
+                if parent != dir {
+                    break;
+                }
+                used += size_of::<IndexedEntry>() + name.len();
+                if entries.len() == limit || used > budget {
+                    return Ok(Some((entries, Some(count))));
+                }
+                entries.push(indexed_entry(name, v.value()));
+            }
+            Ok(Some((entries, None)))
+        })();
+        result.unwrap_or_else(|e| {
+            tracing::warn!("failed to list {path:?} from index: {e}");
+            None
+        })
+    }
+
+    // Like list, but a page in name order: up to page_size entries named after the given name,
+    // whether there are more, and the number of all entries. Blocking.
+    pub fn page(
+        &self,
+        path: &Path,
+        after: Option<&str>,
+        page_size: usize,
+    ) -> Option<(Vec<IndexedEntry>, bool, u64)> {
+        let dir = key(path)?;
+        let result = (|| -> Result<_, DbError> {
+            let txn = self.db.begin_read()?;
+            let Some(count) = self.fresh_count(&txn, dir, path)? else {
+                return Ok(None);
+            };
+            let table = txn.open_table(ENTRIES)?;
+            let start = match after {
+                Some(after) => Bound::Excluded((dir, after)),
+                None => Bound::Included((dir, "")),
+            };
+            let mut entries = Vec::new();
+            for item in table.range::<(&str, &str)>((start, Bound::Unbounded))? {
+                let (k, v) = item?;
+                let (parent, name) = k.value();
+                if parent != dir {
+                    break;
+                }
+                if entries.len() == page_size {
+                    return Ok(Some((entries, true, count)));
+                }
+                entries.push(indexed_entry(name, v.value()));
+            }
+            Ok(Some((entries, false, count)))
+        })();
+        result.unwrap_or_else(|e| {
+            tracing::warn!("failed to list {path:?} from index: {e}");
+            None
+        })
+    }
+
+    // Runs a blocking query (like search) once fewer than query_concurrency are running.
+    pub async fn query<T: Send + 'static>(
+        self: &Arc<Self>,
+        query: impl FnOnce(&Self) -> T + Send + 'static,
+    ) -> Result<T, JoinError> {
+        let _permit = self.queries.acquire().await.unwrap();
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || query(&this)).await
+    }
+
+    // Entries with names containing query case-insensitively, as (parent, entry), in path order,
+    // and whether the index was not walked through. There's no index of names, so entries are
+    // walked until limit is reached or search_max_scan of them are looked at, which is blocking
+    // and could take a while for large trees.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> Result<(Vec<(String, IndexedEntry)>, bool), DbError> {
+        let query = query.to_lowercase();
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+        let mut hits = Vec::new();
+        for (scanned, item) in table.range::<(&str, &str)>(..)?.enumerate() {
+            if hits.len() >= limit || scanned >= self.search_max_scan {
+                return Ok((hits, true));
+            }
+            let (k, v) = item?;
+            let (parent, name) = k.value();
+            if !name.to_lowercase().contains(&query) {
+                continue;
+            }
+            let (size, mtime, is_dir, is_symlink) = v.value();
+            hits.push((
+                parent.to_string(),
+                IndexedEntry {
+                    name: name.to_string(),
+                    size,
+                    mtime,
+                    is_dir,
+                    is_symlink,
+                },
+            ));
+        }
+        Ok((hits, false))
+    }
+
+    // Files (not directories) under a directory modified at or after since (unix timestamp),
+    // as (parent, entry), newest first, and whether there are more than limit of them.
+    // Files are walked newest first by RECENT, until limit of them under the directory are found.
+    pub fn recent(
+        &self,
+        path: &Path,
+        since: i64,
+        limit: usize,
+    ) -> Result<(Vec<(String, IndexedEntry)>, bool), DbError> {
+        let Some(dir) = key(path) else {
+            return Ok((Vec::new(), false));
+        };
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(RECENT)?;
+        let mut hits = Vec::new();
+        for item in table.range((since, "", "")..)?.rev() {
+            let (k, v) = item?;
+            let (mtime, parent, name) = k.value();
+            if !in_subtree(dir, parent) {
+                continue;
+            }
+            if hits.len() == limit {
+                return Ok((hits, true));
+            }
+            let (size, is_symlink) = v.value();
+            hits.push((
+                parent.to_string(),
+                IndexedEntry {
+                    name: name.to_string(),
+                    size,
+                    mtime,
+                    is_dir: false,
+                    is_symlink,
+                },
+            ));
+        }
+        Ok((hits, false))
+    }
+
+    // Total size and number of files (symlinks are not followed) of a directory, and of its
+    // subdirectories down to depth, as (directory, size, files) in path order.
+    pub fn du(&self, path: &Path, depth: usize) -> Result<Vec<(String, u64, u64)>, DbError> {
+        let Some(dir) = key(path) else {
+            return Ok(Vec::new());
+        };
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ENTRIES)?;
+        let mut totals: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        totals.insert(dir.to_string(), (0, 0));
+        for item in subtree(&table, dir)? {
+            let (k, v) = item?;
+            let (size, _, is_dir, is_symlink) = v.value();
+            if is_symlink {
+                continue;
+            }
+            let (parent, name) = k.value();
+            let rest = parent[dir.len()..].trim_start_matches('/');
+            let mut parts: Vec<&str> = rest.split('/').filter(|p| !p.is_empty()).collect();
+            if is_dir {
+                // Shown even if empty
+                parts.push(name);
+                if parts.len() <= depth {
+                    totals.entry(child(dir, &parts.join("/"))).or_default();
+                }
+                continue;
+            }
+            for d in 0..=parts.len().min(depth) {
+                let key = match d {
+                    0 => dir.to_string(),
+                    d => child(dir, &parts[..d].join("/")),
+                };
+                let total = totals.entry(key).or_default();
+                total.0 += size;
+                total.1 += 1;
+            }
+        }
+        Ok(totals
+            .into_iter()
+            .map(|(dir, (size, files))| (dir, size, files))
+            .collect())
+    }
+}
@@ -1,33 +1,80 @@
 // Setup landlock sandboxing to given path only.
 
-use std::process::exit;
+use std::path::Path;
 
+use color_eyre::eyre::WrapErr;
 use landlock::{
-    ABI, Access, AccessFs, CompatLevel, Compatible, PathBeneath, PathFd, Ruleset, RulesetAttr,
-    RulesetCreatedAttr, RulesetStatus,
+    ABI, Access, AccessFs, BitFlags, CompatLevel, Compatible, PathBeneath, PathFd, Ruleset,
+    RulesetAttr, RulesetCreated, RulesetCreatedAttr, RulesetStatus,
 };
 
-use crate::{cmdline::Cmdline, config::Config};
+use yadex::config::{Config, InstanceConfig};
+
+use crate::{ConfigError, cmdline::Cmdline, pid_file};
 
 // Landlock only limits current thread, so it must be called before tokio runtime is created.
+// Rules of all instances are added, as landlock applies to the whole process.
 pub fn setup_landlock(cmdline: &Cmdline, config: &Config) -> color_eyre::Result<()> {
-    let ruleset = Ruleset::default().handle_access(AccessFs::from_all(ABI::V6))?;
-    let mut rules = ruleset
+    let mut rules = new_ruleset()?;
+    for instance in config.instances() {
+        rules = add_instance_rules(rules, cmdline, instance)?;
+    }
+    let status = add_common_rules(rules, cmdline)?.restrict_self()?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => {
+            tracing::info!("Landlock ruleset fully enforced");
+        }
+        RulesetStatus::PartiallyEnforced => {
+            color_eyre::eyre::bail!("Landlock ruleset partially enforced")
+        }
+        RulesetStatus::NotEnforced => color_eyre::eyre::bail!("Landlock ruleset not enforced"),
+    }
+    Ok(())
+}
+
+// For --dry-run: rules of each instance are checked separately, so all problems are reported.
+// If there are none, the ruleset is enforced on a throwaway thread, leaving this one unrestricted.
+pub fn check_landlock(cmdline: &Cmdline, config: &Config) -> Vec<color_eyre::Report> {
+    let mut problems: Vec<_> = config
+        .instances()
+        .filter_map(|instance| add_instance_rules(new_ruleset().ok()?, cmdline, instance).err())
+        .collect();
+    if !problems.is_empty() {
+        return problems;
+    }
+    let enforce = || -> color_eyre::Result<()> {
+        let mut rules = new_ruleset()?;
+        for instance in config.instances() {
+            rules = add_instance_rules(rules, cmdline, instance)?;
+        }
+        match add_common_rules(rules, cmdline)?.restrict_self()?.ruleset {
+            RulesetStatus::FullyEnforced => Ok(()),
+            RulesetStatus::PartiallyEnforced => {
+                color_eyre::eyre::bail!("Landlock ruleset partially enforced")
+            }
+            RulesetStatus::NotEnforced => color_eyre::eyre::bail!("Landlock ruleset not enforced"),
+        }
+    };
+    if let Err(e) = std::thread::scope(|s| s.spawn(enforce).join().unwrap()) {
+        problems.push(e);
+    }
+    problems
+}
+
+fn new_ruleset() -> color_eyre::Result<RulesetCreated> {
+    Ok(Ruleset::default()
+        .handle_access(AccessFs::from_all(ABI::V6))?
         .create()?
-        .set_compatibility(CompatLevel::HardRequirement)
-        .add_rule(PathBeneath::new(
-            PathFd::new(&config.service.root)?,
-            AccessFs::ReadDir,
-        ))?;
+        .set_compatibility(CompatLevel::HardRequirement))
+}
 
-    // Accessing template file
-    let index_path = &cmdline
-        .config
-        .parent()
-        .unwrap()
-        .join(&config.template.index_file);
+fn add_common_rules(
+    mut rules: RulesetCreated,
+    cmdline: &Cmdline,
+) -> color_eyre::Result<RulesetCreated> {
+    // Hostname, shown by templates
     rules = rules.add_rule(PathBeneath::new(
-        PathFd::new(index_path)?,
+        PathFd::new("/proc/sys/kernel/hostname")?,
         AccessFs::ReadFile,
     ))?;
 
@@ -42,19 +89,161 @@ pub fn setup_landlock(cmdline: &Cmdline, config: &Config) -> color_eyre::Result<
             AccessFs::ReadDir | AccessFs::ReadFile,
         ))?;
 
-    let status = rules.restrict_self()?;
-    match status.ruleset {
-        RulesetStatus::FullyEnforced => {
-            tracing::info!("Landlock ruleset fully enforced");
+    // Removing pid file on exit. Landlock only grants removing files on directories (it's a right
+    // of the directory being changed, not of the file), so any file in the directory could be
+    // removed, and a directory of its own is recommended.
+    if let Some(pid_file) = &cmdline.pid_file {
+        rules = rules.add_rule(PathBeneath::new(
+            PathFd::new(pid_file::directory(pid_file))?,
+            AccessFs::RemoveFile,
+        ))?;
+    }
+    Ok(rules)
+}
+
+// Files read as configured (like templates) not existing are errors of configuration, unlike
+// failures of sandboxing itself.
+fn configured(path: impl AsRef<std::path::Path>) -> color_eyre::Result<PathFd> {
+    PathFd::new(path).wrap_err(ConfigError)
+}
+
+// Directory of a configured file, for files replaced by rename (like status written by cron jobs,
+// or redirect maps by editors):
+// a rule on the file itself stays with the old inode, so the new file could not be read.
+fn configured_dir(path: &Path) -> color_eyre::Result<PathFd> {
+    configured(path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => configured(dir),
+        _ => configured("."),
+    }
+}
+
+fn add_instance_rules(
+    rules: RulesetCreated,
+    cmdline: &Cmdline,
+    config: &InstanceConfig,
+) -> color_eyre::Result<RulesetCreated> {
+    // Reading files is needed for previews and thumbnails only
+    let mut root_access: BitFlags<AccessFs> = AccessFs::ReadDir.into();
+    if config.service.markdown_preview || config.service.text_preview || config.thumbnails.is_some()
+    {
+        root_access |= AccessFs::ReadFile;
+    }
+    let mut rules = rules.add_rule(PathBeneath::new(
+        configured(&config.service.root)?,
+        root_access,
+    ))?;
+
+    // Accessing template file, unless a built-in theme is used
+    if config.template.theme.is_none() {
+        let index_path = cmdline
+            .config
+            .parent()
+            .unwrap()
+            .join(&config.template.index_file);
+        rules = rules.add_rule(PathBeneath::new(
+            configured(index_path)?,
+            AccessFs::ReadFile,
+        ))?;
+        if let Some(row_file) = &config.template.row_file {
+            let row_path = cmdline.config.parent().unwrap().join(row_file);
+            rules = rules.add_rule(PathBeneath::new(configured(row_path)?, AccessFs::ReadFile))?;
         }
-        RulesetStatus::PartiallyEnforced => {
-            tracing::error!("Landlock ruleset partially enforced",);
-            exit(1);
+    }
+    if let Some(maintenance_file) = &config.template.maintenance_file {
+        let maintenance_path = cmdline.config.parent().unwrap().join(maintenance_file);
+        rules = rules.add_rule(PathBeneath::new(
+            configured(maintenance_path)?,
+            AccessFs::ReadFile,
+        ))?;
+    }
+    if let Some(assets_dir) = &config.template.assets_dir {
+        let assets_path = cmdline.config.parent().unwrap().join(assets_dir);
+        rules = rules.add_rule(PathBeneath::new(
+            configured(assets_path)?,
+            AccessFs::ReadDir | AccessFs::ReadFile,
+        ))?;
+    }
+    for help in &config.template.help {
+        let help_path = cmdline.config.parent().unwrap().join(&help.file);
+        rules = rules.add_rule(PathBeneath::new(configured(help_path)?, AccessFs::ReadFile))?;
+    }
+
+    // Reading status file, from its directory
+    if let Some(status) = &config.status
+        && !status.source.starts_with("http://")
+    {
+        rules = rules.add_rule(PathBeneath::new(
+            configured_dir(Path::new(&status.source))?,
+            AccessFs::ReadFile,
+        ))?;
+    }
+
+    // GeoIP databases
+    if let Some(geoip) = &config.geoip {
+        for database in [&geoip.country_database, &geoip.asn_database]
+            .into_iter()
+            .flatten()
+        {
+            rules = rules.add_rule(PathBeneath::new(configured(database)?, AccessFs::ReadFile))?;
         }
-        RulesetStatus::NotEnforced => {
-            tracing::error!("Landlock ruleset not enforced");
-            exit(1);
+    }
+
+    // Index database, which could be created
+    if let Some(index) = &config.index {
+        let dir = match index.database.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        rules = rules.add_rule(PathBeneath::new(
+            PathFd::new(dir)?,
+            AccessFs::ReadFile | AccessFs::WriteFile | AccessFs::MakeReg | AccessFs::Truncate,
+        ))?;
+    }
+
+    // Thumbnails cache, created here as rules need it to exist (but not by a dry run)
+    if let Some(thumbnails) = &config.thumbnails
+        && (!cmdline.dry_run || thumbnails.cache_dir.exists())
+    {
+        std::fs::create_dir_all(&thumbnails.cache_dir)?;
+        rules = rules.add_rule(PathBeneath::new(
+            PathFd::new(&thumbnails.cache_dir)?,
+            AccessFs::ReadDir
+                | AccessFs::ReadFile
+                | AccessFs::WriteFile
+                | AccessFs::MakeReg
+                | AccessFs::RemoveFile,
+        ))?;
+    }
+
+    // Redirect map, from its directory
+    if let Some(redirects) = &config.redirects {
+        rules = rules.add_rule(PathBeneath::new(
+            configured_dir(&redirects.file)?,
+            AccessFs::ReadFile,
+        ))?;
+    }
+
+    // Audit log, created here as rules need it to exist (but not by a dry run)
+    if let Some(audit) = &config.audit
+        && (!cmdline.dry_run || audit.file.exists())
+    {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&audit.file)?;
+        rules = rules.add_rule(PathBeneath::new(
+            PathFd::new(&audit.file)?,
+            AccessFs::WriteFile,
+        ))?;
+    }
+
+    // Names of owners
+    if config.service.show_owner {
+        for file in ["/etc/passwd", "/etc/group"] {
+            rules = rules.add_rule(PathBeneath::new(PathFd::new(file)?, AccessFs::ReadFile))?;
         }
     }
-    Ok(())
+
+    Ok(rules)
 }
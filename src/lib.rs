@@ -0,0 +1,33 @@
+// Yet Another inDEX page, as a library: the yadex binary is built on it, and other services could
+// embed directory browsing into their own axum apps with Router::builder().
+
+pub use builder::{Router, RouterBuilder};
+
+mod access;
+mod assets;
+pub mod audit;
+mod builder;
+mod cache;
+mod compress;
+pub mod config;
+mod dirsize;
+pub mod geoip;
+pub mod hotlink;
+pub mod index;
+mod locale;
+pub mod maintenance;
+mod minify;
+pub mod mirrorz;
+mod owner;
+mod preview;
+pub mod redirects;
+pub mod serve;
+pub mod server;
+pub mod shedding;
+pub mod status;
+mod theme;
+#[cfg(feature = "thumbnails")]
+pub mod thumbnail;
+mod tunasync;
+pub mod user_agent;
+mod watcher;
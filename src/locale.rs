@@ -0,0 +1,105 @@
+// Choosing locale of a request from ?hl= or Accept-Language.
+
+// Whether requested language tag is served by supported one, like "zh" by "zh-CN", or "en-US" by "en".
+fn matches(requested: &str, supported: &str) -> bool {
+    let primary = |tag: &str| {
+        tag.split('-')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    };
+    requested.eq_ignore_ascii_case(supported)
+        || !requested.contains('-') && primary(supported) == requested.to_ascii_lowercase()
+        || !supported.contains('-') && primary(requested) == supported.to_ascii_lowercase()
+}
+
+fn find<'a>(supported: &'a [String], requested: &str) -> Option<&'a str> {
+    // Exact matches are preferred
+    supported
+        .iter()
+        .find(|s| s.eq_ignore_ascii_case(requested))
+        .or_else(|| supported.iter().find(|s| matches(requested, s)))
+        .map(String::as_str)
+}
+
+// Like "zh-CN,zh;q=0.9,en;q=0.8", ordered by quality.
+fn parse_accept_language(header: &str) -> Vec<&str> {
+    let mut tags: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && tag != "*" && q > 0.0).then_some((tag, q))
+        })
+        .collect();
+    // Stable sort keeps order of the same quality
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
+
+// Falls back to the first supported locale, or "en" if none configured.
+pub fn negotiate<'a>(
+    supported: &'a [String],
+    hl: Option<&str>,
+    accept_language: Option<&str>,
+) -> &'a str {
+    hl.and_then(|hl| find(supported, hl))
+        .or_else(|| {
+            parse_accept_language(accept_language?)
+                .into_iter()
+                .find_map(|tag| find(supported, tag))
+        })
+        .or(supported.first().map(String::as_str))
+        .unwrap_or("en")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::negotiate;
+
+    fn supported() -> Vec<String> {
+        ["en", "zh-CN", "zh-TW"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn hl_takes_precedence() {
+        let supported = supported();
+        assert_eq!(negotiate(&supported, Some("zh-TW"), Some("en")), "zh-TW");
+        // Unsupported ?hl= falls through to Accept-Language
+        assert_eq!(negotiate(&supported, Some("fr"), Some("zh-CN")), "zh-CN");
+    }
+
+    #[test]
+    fn accept_language_by_quality() {
+        let supported = supported();
+        assert_eq!(
+            negotiate(&supported, None, Some("fr;q=0.9, zh-TW;q=0.8, en;q=0.5")),
+            "zh-TW"
+        );
+        assert_eq!(
+            negotiate(&supported, None, Some("en;q=0.1, zh-cn")),
+            "zh-CN"
+        );
+        assert_eq!(negotiate(&supported, None, Some("zh-CN;q=0, en")), "en");
+    }
+
+    #[test]
+    fn matches_by_primary_language() {
+        let supported = supported();
+        assert_eq!(negotiate(&supported, None, Some("en-US")), "en");
+        assert_eq!(negotiate(&supported, None, Some("zh")), "zh-CN");
+    }
+
+    #[test]
+    fn falls_back_to_first_supported() {
+        let supported = supported();
+        assert_eq!(negotiate(&supported, None, Some("fr, de")), "en");
+        assert_eq!(negotiate(&supported, None, None), "en");
+        assert_eq!(negotiate(&[], Some("zh"), None), "en");
+        assert_eq!(negotiate(&["ja".to_string()], None, None), "ja");
+    }
+}
@@ -1,16 +1,38 @@
+use std::{fmt, process::ExitCode, sync::Arc};
+
+use axum::Router;
 use clap::Parser;
-use cmdline::Cmdline;
-use config::Config;
+use cmdline::{Cmdline, Command};
+use color_eyre::eyre::WrapErr;
 use figment::providers::{Format, Toml};
-use server::{App, Template};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
+};
 use tracing_subscriber::{Layer, filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use yadex::{
+    audit::AuditLog,
+    config::{self, Config, InstanceConfig, NetworkConfig},
+    geoip::GeoRedirect,
+    hotlink::Hotlink,
+    index::FileIndex,
+    maintenance::Maintenance,
+    mirrorz::Mirrorz,
+    redirects::Redirects,
+    serve,
+    server::{self, Components, Template},
+    shedding::LoadShedder,
+    status::StatusStore,
+    user_agent::UserAgentRules,
+};
 
-use crate::landlock::setup_landlock;
+use crate::{landlock::setup_landlock, pid_file::PidFile};
 
+mod bench;
 mod cmdline;
-mod config;
+mod dry_run;
 mod landlock;
-mod server;
+mod pid_file;
 
 fn init_logging() {
     let console_subscriber = tracing_subscriber::fmt::layer()
@@ -29,34 +51,278 @@ fn init_logging() {
         .init();
 }
 
-fn main() -> color_eyre::Result<()> {
+// Exit codes, so that supervisors could tell problems of configuration (which restarting does not
+// help) from failures at runtime, which exit with 1.
+const EXIT_CONFIG: u8 = 78; // EX_CONFIG in sysexits.h
+
+// Context of errors caused by configuration, for the exit code
+#[derive(Debug)]
+struct ConfigError;
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid configuration")
+    }
+}
+
+fn main() -> ExitCode {
     init_logging();
-    color_eyre::install()?;
+    if let Err(e) = color_eyre::install() {
+        eprintln!("Error: {e:?}");
+        return ExitCode::FAILURE;
+    }
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_status(&e))
+        }
+    }
+}
+
+fn exit_status(e: &color_eyre::Report) -> u8 {
+    match e.downcast_ref::<ConfigError>() {
+        Some(_) => EXIT_CONFIG,
+        None => 1,
+    }
+}
+
+fn try_main() -> color_eyre::Result<()> {
     let cmdline = Cmdline::parse();
     tracing::info!("cmdline: {:?}", cmdline);
     let config: Config = figment::Figment::new()
         .merge(Toml::file(&cmdline.config))
-        .extract()?;
+        .extract()
+        .wrap_err(ConfigError)?;
 
-    if config.service.security == config::Security::Landlock {
+    if cmdline.dry_run {
+        return dry_run_main(&cmdline, config);
+    }
+    if let Some(Command::Bench(args)) = &cmdline.command {
+        let rt = build_runtime(&config)?;
+        return rt.block_on(bench::bench(&cmdline, args, config));
+    }
+
+    check_security(&config).wrap_err(ConfigError)?;
+    // Dropped (so removed) on return, after the runtime is shut down
+    let _pid_file = cmdline
+        .pid_file
+        .as_deref()
+        .map(|path| {
+            PidFile::create(
+                path,
+                config.main.service.security == config::Security::Chroot,
+            )
+        })
+        .transpose()?;
+    if config.main.service.security == config::Security::Landlock {
         setup_landlock(&cmdline, &config)?;
     }
 
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()?;
-    rt.block_on(run(cmdline, config))
+    let rt = build_runtime(&config)?;
+    let result = rt.block_on(run(cmdline, config));
+    // Not waiting for blocking tasks, like scans of the index
+    rt.shutdown_background();
+    result
+}
+
+// Sandboxing applies to the whole process
+pub fn check_security(config: &Config) -> color_eyre::Result<()> {
+    let security = &config.main.service.security;
+    if config.instances().any(|i| i.service.security != *security) {
+        color_eyre::eyre::bail!("all instances must use the same security");
+    }
+    if *security == config::Security::Chroot && !config.instance.is_empty() {
+        color_eyre::eyre::bail!("chroot supports a single instance only, use landlock");
+    }
+    Ok(())
+}
+
+fn build_runtime(config: &Config) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(worker_threads) = config.runtime.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = config.runtime.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+    if let Some(thread_stack_size) = config.runtime.thread_stack_size {
+        builder.thread_stack_size(thread_stack_size);
+    }
+    builder.build()
+}
+
+fn dry_run_main(cmdline: &Cmdline, config: Config) -> color_eyre::Result<()> {
+    let rt = build_runtime(&config)?;
+    let problems = rt.block_on(async { dry_run::dry_run(cmdline, config) });
+    for problem in &problems {
+        tracing::error!("{problem}");
+    }
+    if !problems.is_empty() {
+        return Err(color_eyre::eyre::eyre!(
+            "dry run found {} problem(s)",
+            problems.len()
+        ))
+        .wrap_err(ConfigError);
+    }
+    tracing::info!("dry run passed");
+    Ok(())
 }
 
 async fn run(cmdline: Cmdline, config: Config) -> color_eyre::Result<()> {
+    let mut servers = Vec::new();
+    for instance in config.into_instances() {
+        servers.push(setup_instance(&cmdline, instance)?);
+    }
+    // Before notifying, so stopping right after starting is not missed
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
+    let serving = futures_util::future::join_all(servers.into_iter().map(
+        |(listener, router, network)| async move { serve::serve(listener, router, &network).await },
+    ));
+    let signal = tokio::select! {
+        _ = serving => return Ok(()),
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    tracing::info!("received {signal}, exiting");
+    sd_notify::notify(false, &[sd_notify::NotifyState::Stopping])?;
+    Ok(())
+}
+
+fn setup_instance(
+    cmdline: &Cmdline,
+    config: InstanceConfig,
+) -> color_eyre::Result<(TcpListener, Router, NetworkConfig)> {
+    let listener = serve::bind(&config.network)?;
+    tracing::info!("Yadex listening on {}", listener.local_addr()?);
+    let (router, network) = build_router(cmdline, config)?;
+    Ok((listener, router, network))
+}
+
+// Errors of configuration itself (like templates, patterns and maps failing to parse) are marked
+// as ConfigError, while failures of I/O and sandboxing (like opening the index or audit log,
+// chroot and inotify) are not.
+pub fn build_router(
+    cmdline: &Cmdline,
+    config: InstanceConfig,
+) -> color_eyre::Result<(Router, NetworkConfig)> {
+    // Parsed again by the router, but checked here to tell its errors from those of chroot
+    server::Settings::parse(&config.service, &config.network).wrap_err(ConfigError)?;
     let template = match config.service.template_index {
-        true => Template::from_config(&cmdline.config, config.template)?,
+        true => Template::from_config(&cmdline.config, config.template).wrap_err(ConfigError)?,
         false => Template::default(),
     };
-    let listener =
-        tokio::net::TcpListener::bind((config.network.address, config.network.port)).await?;
-    tracing::info!("Yadex listening on {}", listener.local_addr()?);
+    let status = config
+        .status
+        .map(|status| StatusStore::from_config(status, &config.service))
+        .transpose()
+        .wrap_err(ConfigError)?
+        .map(Arc::new);
+    let mirrorz = config
+        .mirrorz
+        .map(|mirrorz| Mirrorz::new(mirrorz, status.clone()));
+    let geoip = config
+        .geoip
+        .map(GeoRedirect::from_config)
+        .transpose()
+        .wrap_err(ConfigError)?;
+    let root = server::fs_root(&config.service).wrap_err(ConfigError)?;
+    let device = server::root_device(&config.service)?;
+    // Opened before chroot, as the database is usually outside root
+    let index = config
+        .index
+        .map(|index| FileIndex::from_config(index, root, device))
+        .transpose()?
+        .map(Arc::new);
+    let redirects = config
+        .redirects
+        .map(|redirects| Redirects::from_config(redirects, &config.service))
+        .transpose()
+        .wrap_err(ConfigError)?
+        .map(Arc::new);
+    #[cfg(feature = "thumbnails")]
+    let thumbnails = match config.thumbnails {
+        // The cache is outside root, usually
+        Some(_) if config.service.security == config::Security::Chroot => {
+            return Err(color_eyre::eyre::eyre!(
+                "thumbnails are not supported with chroot, use landlock"
+            )
+            .wrap_err(ConfigError));
+        }
+        thumbnails => thumbnails
+            .map(yadex::thumbnail::Thumbnails::from_config)
+            .transpose()?
+            .map(Arc::new),
+    };
+    #[cfg(not(feature = "thumbnails"))]
+    if config.thumbnails.is_some() {
+        return Err(color_eyre::eyre::eyre!(
+            "[thumbnails] requires yadex built with the \"thumbnails\" feature"
+        )
+        .wrap_err(ConfigError));
+    }
+    let maintenance = config
+        .maintenance
+        .map(Maintenance::from_config)
+        .map(Arc::new);
+    let shedder = config.shedding.map(LoadShedder::from_config).map(Arc::new);
+    let audit = config.audit.map(AuditLog::open).transpose()?.map(Arc::new);
+    let user_agents = config
+        .user_agents
+        .map(UserAgentRules::from_config)
+        .transpose()
+        .wrap_err(ConfigError)?
+        .map(Arc::new);
+    let hotlink = config.hotlink.map(Hotlink::from_config).map(Arc::new);
 
-    App::serve(config.service, listener, template).await?;
-    Ok(())
+    let router = yadex::Router::builder()
+        .service(config.service)
+        .network(config.network.clone())
+        .template(template)
+        .components(Components {
+            mirrorz,
+            status,
+            geoip,
+            index,
+            redirects,
+            maintenance,
+            shedder,
+            audit,
+            user_agents,
+            hotlink,
+            #[cfg(feature = "thumbnails")]
+            thumbnails,
+        })
+        .build()?;
+    Ok((router, config.network))
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::{WrapErr, eyre};
+
+    use super::*;
+
+    #[test]
+    fn config_errors_exit_with_ex_config() {
+        let parse = Err::<(), _>(std::io::Error::other("bad toml")).wrap_err(ConfigError);
+        assert_eq!(exit_status(&parse.unwrap_err()), EXIT_CONFIG);
+        // Still found under more context
+        let wrapped = Err::<(), _>(eyre!("bad regex").wrap_err(ConfigError))
+            .wrap_err("instance 1")
+            .unwrap_err();
+        assert_eq!(exit_status(&wrapped), EXIT_CONFIG);
+    }
+
+    #[test]
+    fn runtime_failures_exit_with_1() {
+        assert_eq!(exit_status(&eyre!("failed to chroot")), 1);
+        let bind = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::AddrInUse))
+            .wrap_err("failed to bind")
+            .unwrap_err();
+        assert_eq!(exit_status(&bind), 1);
+    }
 }
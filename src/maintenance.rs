@@ -0,0 +1,173 @@
+// Answering requests with 503 while the mirror (or part of it) is in maintenance, toggled by
+// presence of flag files, like while the storage is being remounted or a repo is being re-synced.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{config::MaintenanceConfig, status::normalize_prefix};
+
+// Used unless maintenance_file is set in [template]
+pub const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="{{locale}}">
+<head>
+<meta charset="UTF-8" />
+<meta name="viewport" content="width=device-width, initial-scale=1.0" />
+<title>{{t "Under maintenance"}}</title>
+</head>
+<body>
+<h1>{{t "Under maintenance"}}</h1>
+{{#if prefix}}
+<p>{{t "{prefix} is under maintenance. Please try again later." prefix=prefix}}</p>
+{{else}}
+<p>{{t "This mirror is under maintenance. Please try again later."}}</p>
+{{/if}}
+{{#if message}}<p>{{message}}</p>{{/if}}
+</body>
+</html>
+"#;
+
+// Active while flag_file exists, or always without one.
+struct Flag {
+    flag_file: Option<PathBuf>,
+    active: AtomicBool,
+}
+
+impl Flag {
+    fn new(flag_file: Option<PathBuf>) -> Self {
+        Self {
+            active: AtomicBool::new(flag_file.is_none()),
+            flag_file,
+        }
+    }
+
+    // Whether it changed
+    fn check(&self) -> bool {
+        let Some(flag_file) = &self.flag_file else {
+            return false;
+        };
+        let active = flag_file.try_exists().unwrap_or(false);
+        self.active.swap(active, Ordering::Relaxed) != active
+    }
+
+    fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+struct Prefix {
+    // Normalized, like "/debian/"
+    prefix: String,
+    message: Option<String>,
+    flag: Flag,
+}
+
+// What is in maintenance for a request
+pub struct Matched<'a> {
+    // None if the whole mirror is
+    pub prefix: Option<&'a str>,
+    pub message: Option<&'a str>,
+}
+
+pub struct Maintenance {
+    flag: Option<Flag>,
+    // Longest first
+    prefixes: Vec<Prefix>,
+    // Seconds, for Retry-After
+    retry_after: u64,
+    check_interval: Duration,
+}
+
+impl Maintenance {
+    pub fn from_config(config: MaintenanceConfig) -> Self {
+        let mut prefixes: Vec<_> = config
+            .prefixes
+            .into_iter()
+            .map(|p| Prefix {
+                prefix: normalize_prefix(&p.prefix),
+                message: p.message,
+                flag: Flag::new(p.flag_file),
+            })
+            .collect();
+        prefixes.sort_by_key(|p| std::cmp::Reverse(p.prefix.len()));
+        let maintenance = Self {
+            flag: config.flag_file.map(|f| Flag::new(Some(f))),
+            prefixes,
+            retry_after: config.retry_after,
+            check_interval: Duration::from_secs(config.check_interval.max(1)),
+        };
+        maintenance.check();
+        maintenance
+    }
+
+    // Blocking, which is fine for a few stats now and then.
+    fn check(&self) {
+        if let Some(flag) = &self.flag
+            && flag.check()
+        {
+            match flag.active() {
+                true => tracing::warn!("entering maintenance, as {:?} exists", flag.flag_file),
+                false => tracing::info!("leaving maintenance"),
+            }
+        }
+        for prefix in &self.prefixes {
+            if prefix.flag.check() {
+                match prefix.flag.active() {
+                    true => tracing::warn!(
+                        "entering maintenance of {}, as {:?} exists",
+                        prefix.prefix,
+                        prefix.flag.flag_file
+                    ),
+                    false => tracing::info!("leaving maintenance of {}", prefix.prefix),
+                }
+            }
+        }
+    }
+
+    pub fn spawn_check(self: &Arc<Self>) {
+        let watched =
+            self.flag.is_some() || self.prefixes.iter().any(|p| p.flag.flag_file.is_some());
+        if !watched {
+            return;
+        }
+        let maintenance = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(maintenance.check_interval).await;
+                let this = maintenance.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || this.check()).await {
+                    tracing::warn!("failed to check maintenance flags: {e}");
+                }
+            }
+        });
+    }
+
+    // Decoded path of the request, like "/debian/dists/". "/debian" matches prefix "/debian/" too.
+    pub fn matches(&self, path: &str) -> Option<Matched<'_>> {
+        if self.flag.as_ref().is_some_and(Flag::active) {
+            return Some(Matched {
+                prefix: None,
+                message: None,
+            });
+        }
+        self.prefixes
+            .iter()
+            .find(|p| {
+                p.flag.active()
+                    && (path.starts_with(&p.prefix) || p.prefix.strip_suffix('/') == Some(path))
+            })
+            .map(|p| Matched {
+                prefix: Some(&p.prefix),
+                message: p.message.as_deref(),
+            })
+    }
+
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+}
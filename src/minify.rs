@@ -0,0 +1,82 @@
+// Collapsing whitespace of rendered pages, like indentation of templates repeated in every row.
+
+// Contents of these are kept as is, as whitespace matters there.
+const RAW_ELEMENTS: [&str; 4] = ["pre", "textarea", "script", "style"];
+
+// Name of the element opened by a tag, like "pre" for "<pre class=...>".
+fn tag_name(tag: &str) -> &str {
+    let name = &tag[1..];
+    let end = name
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(name.len());
+    &name[..end]
+}
+
+// Length of the tag at start of html, up to and including ">", with quoted attributes skipped.
+fn tag_len(html: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    html.len()
+}
+
+// Runs of whitespace become a newline if they contain one, or a space otherwise.
+fn collapse(text: &str, out: &mut String) {
+    let mut run: Option<bool> = None;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            run = Some(run.unwrap_or(false) || c == '\n');
+            continue;
+        }
+        if let Some(newline) = run.take() {
+            out.push(if newline { '\n' } else { ' ' });
+        }
+        out.push(c);
+    }
+    if let Some(newline) = run {
+        out.push(if newline { '\n' } else { ' ' });
+    }
+}
+
+// Tags, comments and raw elements are kept, and only whitespace of text between them is collapsed,
+// which never changes how pages are rendered.
+pub fn collapse_whitespace(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        collapse(&rest[..start], &mut out);
+        rest = &rest[start..];
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map_or(rest.len(), |end| end + 3)
+        } else {
+            let tag = tag_len(rest);
+            let name = tag_name(rest);
+            match RAW_ELEMENTS
+                .iter()
+                .find(|raw| raw.eq_ignore_ascii_case(name))
+            {
+                Some(raw) => {
+                    let closing = format!("</{raw}");
+                    rest[tag..]
+                        .to_ascii_lowercase()
+                        .find(&closing)
+                        .map_or(rest.len(), |close| {
+                            let close = tag + close;
+                            close + tag_len(&rest[close..])
+                        })
+                }
+                None => tag,
+            }
+        };
+        out.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    collapse(rest, &mut out);
+    out
+}
@@ -0,0 +1,116 @@
+// Generating mirrorz.json (https://github.com/mirrorz-org/mirrorz) from config and sync status.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    config::MirrorzConfig,
+    status::{StatusStore, SyncInfo},
+};
+
+const MIRRORZ_VERSION: f64 = 1.7;
+
+pub struct Mirrorz {
+    config: MirrorzConfig,
+    status: Option<Arc<StatusStore>>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: f64,
+    site: &'a serde_json::Value,
+    info: &'a [serde_json::Value],
+    mirrors: Vec<MirrorEntry>,
+}
+
+#[derive(Serialize)]
+struct MirrorEntry {
+    cname: String,
+    desc: String,
+    url: String,
+    status: String,
+    help: String,
+    upstream: String,
+    size: String,
+}
+
+// See "status" in mirrorz's data format: a sequence of state letters, each followed by a timestamp.
+fn mirrorz_status(info: &SyncInfo) -> String {
+    let mut status = match info.status.as_str() {
+        "success" => format!("S{}", info.last_update),
+        "syncing" | "pre-syncing" => format!("Y{}O{}", info.last_started, info.last_update),
+        "failed" => format!("F{}O{}", info.last_ended, info.last_update),
+        "paused" | "disabled" => "P".to_string(),
+        _ => "U".to_string(),
+    };
+    if info.next_schedule > 0 {
+        status.push_str(&format!("X{}", info.next_schedule));
+    }
+    status
+}
+
+impl Mirrorz {
+    pub fn new(config: MirrorzConfig, status: Option<Arc<StatusStore>>) -> Self {
+        Self { config, status }
+    }
+
+    pub fn document(&self) -> serde_json::Value {
+        let status = self
+            .status
+            .as_ref()
+            .map(|store| store.snapshot())
+            .unwrap_or_default();
+
+        let mut mirrors = BTreeMap::new();
+        // Only top-level directories are considered as mirrors.
+        for (prefix, info) in status.iter() {
+            let name = prefix.trim_matches('/');
+            if name.is_empty() || name.contains('/') {
+                continue;
+            }
+            mirrors.insert(
+                name.to_string(),
+                MirrorEntry {
+                    cname: name.to_string(),
+                    desc: String::new(),
+                    url: format!("/{name}"),
+                    status: mirrorz_status(info),
+                    help: String::new(),
+                    upstream: info.upstream.clone(),
+                    size: info.size.clone(),
+                },
+            );
+        }
+        // Mirrors from config take precedence over what status source reports, except for status.
+        for m in &self.config.mirrors {
+            let entry = mirrors
+                .entry(m.cname.clone())
+                .or_insert_with(|| MirrorEntry {
+                    cname: m.cname.clone(),
+                    desc: String::new(),
+                    url: format!("/{}", m.cname),
+                    status: "U".to_string(),
+                    help: String::new(),
+                    upstream: String::new(),
+                    size: String::new(),
+                });
+            entry.desc.clone_from(&m.desc);
+            entry.help.clone_from(&m.help);
+            if let Some(url) = &m.url {
+                entry.url.clone_from(url);
+            }
+            if !m.upstream.is_empty() {
+                entry.upstream.clone_from(&m.upstream);
+            }
+        }
+
+        serde_json::to_value(Document {
+            version: MIRRORZ_VERSION,
+            site: &self.config.site,
+            info: &self.config.info,
+            mirrors: mirrors.into_values().collect(),
+        })
+        .expect("mirrorz document is always serializable")
+    }
+}
@@ -0,0 +1,136 @@
+// Owners and permissions of entries, for inspecting storage trees.
+
+use std::{collections::HashMap, fs, os::unix::fs::FileTypeExt, os::unix::fs::MetadataExt};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnerInfo {
+    pub mode: u32,
+    // Like "drwxr-xr-x"
+    pub mode_str: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+// User and group names, loaded at startup as they may not be accessible after sandboxing.
+#[derive(Default)]
+pub struct Owners {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+// Parses "name:x:id:..." lines of /etc/passwd or /etc/group.
+fn parse_id_names(content: &str) -> HashMap<u32, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+fn mode_str(meta: &fs::Metadata) -> String {
+    let ft = meta.file_type();
+    let kind = if ft.is_dir() {
+        'd'
+    } else if ft.is_symlink() {
+        'l'
+    } else if ft.is_fifo() {
+        'p'
+    } else if ft.is_socket() {
+        's'
+    } else if ft.is_char_device() {
+        'c'
+    } else if ft.is_block_device() {
+        'b'
+    } else {
+        '-'
+    };
+    let mode = meta.mode();
+    let mut s = String::with_capacity(10);
+    s.push(kind);
+    // (read, write, execute bits, special bit, special char)
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+impl Owners {
+    pub fn load() -> Self {
+        let read = |path| {
+            fs::read_to_string(path)
+                .map(|content| parse_id_names(&content))
+                .unwrap_or_else(|e| {
+                    tracing::warn!("failed to read {path}, names of owners would be missing: {e}");
+                    HashMap::new()
+                })
+        };
+        Self {
+            users: read("/etc/passwd"),
+            groups: read("/etc/group"),
+        }
+    }
+
+    pub fn info(&self, meta: &fs::Metadata) -> OwnerInfo {
+        OwnerInfo {
+            mode: meta.mode() & 0o7777,
+            mode_str: mode_str(meta),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            user: self.users.get(&meta.uid()).cloned(),
+            group: self.groups.get(&meta.gid()).cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{mode_str, parse_id_names};
+
+    #[test]
+    fn parses_passwd_and_group() {
+        let names = parse_id_names(
+            "root:x:0:0:root:/root:/bin/bash\nmirror:x:1000:1000::/srv:/bin/sh\n\nbroken\nbad:x:id:\n",
+        );
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&0], "root");
+        assert_eq!(names[&1000], "mirror");
+        assert_eq!(parse_id_names("sudo:x:27:alice,bob")[&27], "sudo");
+    }
+
+    #[test]
+    fn formats_modes_like_ls() {
+        let dir = std::env::temp_dir().join(format!("yadex-mode-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        let check = |path: &std::path::Path, mode: u32, expected: &str| {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+            assert_eq!(mode_str(&fs::symlink_metadata(path).unwrap()), expected);
+        };
+        check(&file, 0o644, "-rw-r--r--");
+        check(&file, 0o4755, "-rwsr-xr-x");
+        check(&file, 0o2644, "-rw-r-Sr--");
+        check(&dir, 0o1777, "drwxrwxrwt");
+        check(&dir, 0o1754, "drwxr-xr-T");
+        check(&dir, 0o755, "drwxr-xr-x");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+// --pid-file: supervisors other than systemd (like runit and OpenRC) find yadex by the PID written.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::WrapErr;
+
+pub struct PidFile {
+    path: PathBuf,
+    // After chroot, the path would point to somewhere else (if anywhere)
+    remove: bool,
+}
+
+impl PidFile {
+    // Written before sandboxing, as the file is outside root, usually. A stale one left by a
+    // previous run is overwritten.
+    pub fn create(path: &Path, chroot: bool) -> color_eyre::Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .wrap_err_with(|| format!("failed to write pid file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            remove: !chroot,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if self.remove
+            && let Err(e) = fs::remove_file(&self.path)
+        {
+            tracing::warn!("failed to remove pid file {}: {e}", self.path.display());
+        }
+    }
+}
+
+// Where the pid file is created, and removed from
+pub fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
@@ -0,0 +1,141 @@
+// Rendering files into HTML shown inside the site template, for ?view=rendered and ?view=text.
+
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::LazyLock,
+};
+
+use pulldown_cmark::{Options, Parser};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{IncludeBackground, styled_line_to_highlighted_html},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME: LazyLock<Theme> = LazyLock::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("InspiredGitHub")
+        .expect("InspiredGitHub is a default theme")
+});
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum View {
+    // Markdown rendered into HTML
+    Rendered,
+    // Highlighted source with line numbers
+    Text,
+}
+
+impl View {
+    pub fn from_query(view: &str) -> Option<Self> {
+        match view {
+            "rendered" => Some(Self::Rendered),
+            "text" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rendered => "rendered",
+            Self::Text => "text",
+        }
+    }
+
+    // None if the file could not be shown this way, like binary files as text.
+    pub fn render(self, path: &Path, content: &[u8]) -> Option<String> {
+        match self {
+            Self::Rendered => Some(render_markdown(&String::from_utf8_lossy(content))),
+            Self::Text => {
+                if content.contains(&0) {
+                    return None;
+                }
+                Some(render_text(path, &String::from_utf8_lossy(content)))
+            }
+        }
+    }
+}
+
+// Whether the file could be previewed by its name, like "README.md".
+pub fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
+}
+
+// None if the file is larger than max_size.
+pub fn read_capped(path: &Path, max_size: u64) -> io::Result<Option<Vec<u8>>> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    if metadata.len() > max_size {
+        return Ok(None);
+    }
+    // The file could grow after stat
+    let mut content = Vec::new();
+    file.take(max_size + 1).read_to_end(&mut content)?;
+    if content.len() as u64 > max_size {
+        return Ok(None);
+    }
+    Ok(Some(content))
+}
+
+// Raw HTML in Markdown is kept, but sanitized together with the rest.
+fn render_markdown(source: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    let mut html = String::with_capacity(source.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(source, options));
+    ammonia::clean(&html)
+}
+
+// By file name (like "Makefile"), extension, then first line (like "#!/bin/sh").
+fn find_syntax<'a>(path: &Path, source: &str) -> &'a SyntaxReference {
+    let by_name = |name: Option<&std::ffi::OsStr>| {
+        name.and_then(|name| name.to_str())
+            .and_then(|name| SYNTAXES.find_syntax_by_extension(name))
+    };
+    by_name(path.file_name())
+        .or_else(|| by_name(path.extension()))
+        .or_else(|| SYNTAXES.find_syntax_by_first_line(source.lines().next().unwrap_or_default()))
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text())
+}
+
+// Lines are anchored as #L1, #L2, ... Unknown syntaxes are shown as plain text.
+fn render_text(path: &Path, source: &str) -> String {
+    let mut highlighter = HighlightLines::new(find_syntax(path, source), &THEME);
+    let mut html = String::with_capacity(source.len() * 4);
+    html.push_str("<pre class=\"code\"");
+    if let Some(bg) = THEME.settings.background {
+        let _ = write!(
+            html,
+            " style=\"background-color:#{:02x}{:02x}{:02x};\"",
+            bg.r, bg.g, bg.b
+        );
+    }
+    html.push_str("><code>");
+    for (i, line) in LinesWithEndings::from(source).enumerate() {
+        let n = i + 1;
+        let highlighted = highlighter
+            .highlight_line(line, &SYNTAXES)
+            .and_then(|regions| styled_line_to_highlighted_html(&regions, IncludeBackground::No))
+            .unwrap_or_else(|_| html_escape::encode_text(line).into_owned());
+        let _ = write!(
+            html,
+            "<span class=\"line\" id=\"L{n}\"><a class=\"line-number\" href=\"#L{n}\">{n}</a>{highlighted}</span>"
+        );
+    }
+    html.push_str("</code></pre>");
+    html
+}
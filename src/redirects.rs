@@ -0,0 +1,131 @@
+// Redirecting legacy paths by a map file, reloaded when the file changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::http::StatusCode;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::config::{RedirectsConfig, ServiceConfig};
+
+#[derive(Debug, Snafu)]
+pub enum RedirectsLoadError {
+    #[snafu(display("failed to read redirects from {path:?}: {source}"))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("invalid redirect at {path:?} line {line}"))]
+    Line { path: PathBuf, line: usize },
+    #[snafu(display("invalid redirect status {status}"))]
+    Status { status: u16 },
+}
+
+// Decoded paths, like "/debian-cd/", to targets
+type RedirectMap = HashMap<String, String>;
+
+pub struct Redirects {
+    path: PathBuf,
+    status: StatusCode,
+    reload_interval: Duration,
+    current: RwLock<(Option<SystemTime>, Arc<RedirectMap>)>,
+}
+
+fn decode(path: &str) -> String {
+    urlencoding::decode(path)
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+// One redirect per line, as "path target" or "path,target". Empty lines and lines starting with "#" are skipped.
+fn parse(path: &Path, content: &str) -> Result<RedirectMap, RedirectsLoadError> {
+    let mut map = RedirectMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (from, to) = line
+            .split_once(|c: char| c == ',' || c.is_whitespace())
+            .map(|(from, to)| (from.trim(), to.trim()))
+            .filter(|(from, to)| from.starts_with('/') && !to.is_empty())
+            .context(LineSnafu { path, line: i + 1 })?;
+        map.insert(decode(from), to.to_string());
+    }
+    Ok(map)
+}
+
+impl Redirects {
+    // Loaded once here, so that mistakes are found at startup. With chroot, that's from inside root,
+    // where it's reloaded from later.
+    pub fn from_config(
+        config: RedirectsConfig,
+        service: &ServiceConfig,
+    ) -> Result<Self, RedirectsLoadError> {
+        let status = StatusCode::from_u16(config.status)
+            .ok()
+            .filter(StatusCode::is_redirection)
+            .context(StatusSnafu {
+                status: config.status,
+            })?;
+        let redirects = Self {
+            path: config.file,
+            status,
+            reload_interval: Duration::from_secs(config.reload_interval.max(1)),
+            current: RwLock::default(),
+        };
+        redirects.load(&service.before_chroot(&redirects.path))?;
+        Ok(redirects)
+    }
+
+    fn reload(&self) -> Result<(), RedirectsLoadError> {
+        self.load(&self.path)
+    }
+
+    // Blocking, which is fine for a single file read now and then.
+    fn load(&self, path: &Path) -> Result<(), RedirectsLoadError> {
+        let modified = std::fs::metadata(path).ok().and_then(|m| m.modified().ok());
+        if modified.is_some() && modified == self.current.read().unwrap().0 {
+            return Ok(());
+        }
+        let content = std::fs::read_to_string(path).context(ReadSnafu { path })?;
+        let map = parse(path, &content)?;
+        tracing::info!("loaded {} redirects from {:?}", map.len(), path);
+        *self.current.write().unwrap() = (modified, Arc::new(map));
+        Ok(())
+    }
+
+    // Keeps the last loaded map when reloading fails.
+    pub fn spawn_reload(self: &Arc<Self>) {
+        let redirects = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(redirects.reload_interval).await;
+                let this = redirects.clone();
+                match tokio::task::spawn_blocking(move || this.reload()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::warn!("failed to reload redirects: {e}"),
+                    Err(e) => tracing::warn!("failed to reload redirects: {e}"),
+                }
+            }
+        });
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    // Target for a request path (still percent-encoded), with query appended if the target has none.
+    pub fn redirect(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let current = self.current.read().unwrap();
+        let target = current.1.get(&decode(path))?;
+        Some(match query {
+            Some(query) if !target.contains('?') => format!("{target}?{query}"),
+            _ => target.clone(),
+        })
+    }
+}
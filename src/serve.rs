@@ -0,0 +1,95 @@
+// Accepting and serving connections, with TCP and keep-alive tuning options.
+
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{HeaderValue, Version, header},
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+};
+use tokio::net::{TcpListener, TcpSocket};
+use tower_service::Service;
+
+use crate::config::NetworkConfig;
+
+pub fn bind(config: &NetworkConfig) -> io::Result<TcpListener> {
+    let addr = SocketAddr::new(config.address, config.port);
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(config.backlog)
+}
+
+// Like axum::serve, but with options applied to each connection.
+pub async fn serve(listener: TcpListener, router: Router, config: &NetworkConfig) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    if config.keep_alive_timeout > 0 {
+        // Waiting for the next request on a kept-alive connection is waiting for its header
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_secs(config.keep_alive_timeout));
+    }
+    let max_requests = config.max_requests_per_connection;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Like running out of file descriptors, which may recover later
+                tracing::warn!("failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if config.tcp_nodelay
+            && let Err(e) = stream.set_nodelay(true)
+        {
+            tracing::warn!("failed to set TCP_NODELAY: {e}");
+        }
+        let router = router.clone();
+        let served = Arc::new(AtomicU64::new(0));
+        let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(peer));
+            let last = max_requests > 0
+                && req.version() < Version::HTTP_2
+                && served.fetch_add(1, Ordering::Relaxed) + 1 >= max_requests;
+            let response = router.clone().call(req.map(Body::new));
+            async move {
+                let mut response = response.await?;
+                if last {
+                    response
+                        .headers_mut()
+                        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                }
+                Ok::<_, Infallible>(response)
+            }
+        });
+        let builder = builder.clone();
+        tokio::spawn(async move {
+            if let Err(e) = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::trace!("failed to serve connection from {peer}: {e:#}");
+            }
+        });
+    }
+}
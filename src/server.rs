@@ -1,34 +1,100 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     env::set_current_dir,
-    fs, io,
+    ffi::OsStr,
+    fs,
+    hash::{DefaultHasher, Hasher},
+    net::{IpAddr, SocketAddr},
     os::unix::fs::{MetadataExt, chroot},
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{
     Router,
-    extract::{Json, State},
-    http::Uri,
-    response::{Html, IntoResponse, Redirect, Response},
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Extension, Json, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, header},
+    middleware::{self, Next},
+    response::{
+        Html, IntoResponse, Redirect, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::{get, post},
 };
-use chrono::{TimeZone, Utc};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono_tz::Tz;
 use futures_util::StreamExt as SExt;
-use handlebars::{RenderError, handlebars_helper};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, RenderErrorReason,
+    ScopedJson, handlebars_helper,
+};
 use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
-use tokio::{fs::DirEntry, net::TcpListener};
+use tokio::{fs::DirEntry, sync::broadcast::error::RecvError};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::error;
 
-use crate::config::{ServiceConfig, TemplateConfig};
+#[cfg(feature = "thumbnails")]
+use crate::thumbnail::Thumbnails;
+use crate::{
+    access,
+    assets::Assets,
+    audit::{self, AuditLog, Audited},
+    cache::ListingCache,
+    compress::{self, CompressedCache, Encoding},
+    config::{NetworkConfig, Order, ServiceConfig, SizeUnits, TemplateConfig, Theme},
+    dirsize::DirSizeCache,
+    geoip::GeoRedirect,
+    hotlink::{self, Hotlink},
+    index::{FileIndex, IndexedEntry},
+    locale::negotiate,
+    maintenance::{self, Maintenance},
+    minify,
+    mirrorz::Mirrorz,
+    owner::{OwnerInfo, Owners},
+    preview::{self, View},
+    redirects::Redirects,
+    shedding::{Admission, LoadShedder},
+    status::{StatusStore, SyncInfo, longest_prefix, normalize_prefix},
+    user_agent::{UserAgentRules, Verdict},
+    watcher::DirWatcher,
+};
 
 pub struct App {}
 
+// Optional components, set up before sandboxing.
+#[derive(Default)]
+pub struct Components {
+    pub mirrorz: Option<Mirrorz>,
+    pub status: Option<Arc<StatusStore>>,
+    pub geoip: Option<GeoRedirect>,
+    pub index: Option<Arc<FileIndex>>,
+    pub redirects: Option<Arc<Redirects>>,
+    pub maintenance: Option<Arc<Maintenance>>,
+    pub shedder: Option<Arc<LoadShedder>>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub user_agents: Option<Arc<UserAgentRules>>,
+    pub hotlink: Option<Arc<Hotlink>>,
+    #[cfg(feature = "thumbnails")]
+    pub thumbnails: Option<Arc<Thumbnails>>,
+}
+
 #[derive(Default)]
 pub struct Template {
     registry: handlebars::Handlebars<'static>,
+    // Prefixes with help registered as "help:{prefix}", longest first
+    help_prefixes: Vec<String>,
+    timezone: Tz,
+    locales: Vec<String>,
+    // Whether "row" is registered, which streaming requires
+    has_row: bool,
+    stream_threshold: usize,
+    // Collapse whitespace of rendered pages and rows
+    minify: bool,
+    // Served under /_yadex/assets/, with "theme.css" of the built-in theme if used
+    assets: Assets,
 }
 
 #[derive(Debug, Snafu)]
@@ -43,32 +109,146 @@ pub enum TemplateLoadError {
         component: &'static str,
         source: handlebars::TemplateError,
     },
+    #[snafu(display("unknown timezone {name:?}"))]
+    Timezone {
+        name: String,
+        source: chrono_tz::ParseError,
+    },
+}
+
+// Note: This gives time in configured timezone (UTC by default) to frontend, which is also given as "timezone".
+// Format is chosen by "locale" in data.
+struct FromMtimestampHelper {
+    timezone: Tz,
+    date_formats: HashMap<String, String>,
 }
 
-// Note: This gives UTC time to frontend. It's expected that frontend shall convert to users' timezone, if necessary.
-handlebars_helper!(from_mtimestamp_helper: |t: i64| {
-    match chrono::DateTime::from_timestamp(t, 0) {
-        Some(dt) => Utc
-            .timestamp_opt(dt.timestamp(), 0)
-            .single()
-            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
-            .unwrap_or_else(|| "Invalid timestamp".to_string()),
-        None => "Invalid timestamp".to_string(),
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+impl HelperDef for FromMtimestampHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let format = ctx
+            .data()
+            .get("locale")
+            .and_then(|locale| locale.as_str())
+            .and_then(|locale| self.date_formats.get(locale))
+            .map_or(DEFAULT_DATE_FORMAT, String::as_str);
+        let t = h.param(0).and_then(|v| v.value().as_i64()).ok_or(
+            RenderErrorReason::ParamNotFoundForIndex("from_mtimestamp", 0),
+        )?;
+        let formatted = match chrono::DateTime::from_timestamp(t, 0) {
+            Some(dt) => dt.with_timezone(&self.timezone).format(format).to_string(),
+            None => "Invalid timestamp".to_string(),
+        };
+        Ok(ScopedJson::Derived(serde_json::Value::String(formatted)))
     }
-});
+}
 
-handlebars_helper!(humanize_size_helper: |s: u64| {
-    if s >= 1 << 30 {
-        format!("{:.2} GiB", s as f64 / (1 << 30) as f64)
-    } else if s >= 1 << 20 {
-        format!("{:.2} MiB", s as f64 / (1 << 20) as f64)
-    } else if s >= 1 << 10 {
-        format!("{:.2} KiB", s as f64 / (1 << 10) as f64)
-    } else {
-        format!("{} B", s)
+// {{t "Name"}} gives "Name" translated to "locale" in data, or as is if not translated.
+// Hash parameters fill placeholders, like {{t "updated {time}" time=...}}.
+struct TranslateHelper {
+    strings: HashMap<String, HashMap<String, String>>,
+}
+
+impl HelperDef for TranslateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let key = h
+            .param(0)
+            .and_then(|v| v.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("t", 0))?;
+        let mut translated = ctx
+            .data()
+            .get("locale")
+            .and_then(|locale| locale.as_str())
+            .and_then(|locale| self.strings.get(locale))
+            .and_then(|strings| strings.get(key))
+            .map_or(key, String::as_str)
+            .to_string();
+        for (name, value) in h.hash() {
+            let value = match value.value() {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            translated = translated.replace(&format!("{{{name}}}"), &value);
+        }
+        Ok(ScopedJson::Derived(serde_json::Value::String(translated)))
     }
+}
+
+// {{#if (is_image name)}} for names of images that could have thumbnails.
+handlebars_helper!(is_image: |name: str| {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ["png", "jpg", "jpeg", "gif", "webp"]
+                .iter()
+                .any(|image| ext.eq_ignore_ascii_case(image))
+        })
 });
 
+fn humanize_size(s: u64, units: SizeUnits) -> String {
+    let (base, suffixes): (u64, [&str; 3]) = match units {
+        SizeUnits::Binary => (1 << 10, ["KiB", "MiB", "GiB"]),
+        SizeUnits::SI => (1000, ["kB", "MB", "GB"]),
+    };
+    let mut scale = base.pow(suffixes.len() as u32);
+    for suffix in suffixes.iter().rev() {
+        if s >= scale {
+            return format!("{:.2} {suffix}", s as f64 / scale as f64);
+        }
+        scale /= base;
+    }
+    format!("{} B", s)
+}
+
+struct HumanizeSizeHelper {
+    units: SizeUnits,
+}
+
+impl HelperDef for HumanizeSizeHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let s = h
+            .param(0)
+            .and_then(|v| v.value().as_u64())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("humanize_size", 0))?;
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            humanize_size(s, self.units),
+        )))
+    }
+}
+
+// Like "1,234,567"
+fn exact_size(s: u64) -> String {
+    let digits = s.to_string();
+    let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            result.push(',');
+        }
+        result.push(c);
+    }
+    result
+}
+
 impl Template {
     pub fn from_config(
         path_to_config: &Path,
@@ -76,67 +256,886 @@ impl Template {
     ) -> Result<Self, TemplateLoadError> {
         let mut registry = handlebars::Handlebars::new();
         let config_dir = path_to_config.parent().unwrap();
-        let index_path = config_dir.join(config.index_file);
-        let index = std::fs::read_to_string(&index_path).context(IoSnafu {
-            component: "index",
-            path: index_path,
-        })?;
+        let (has_row, css) = match config.theme {
+            Some(theme) => {
+                let files = crate::theme::files(theme);
+                registry
+                    .register_template_string("index", files.index)
+                    .context(RegisterSnafu { component: "index" })?;
+                registry
+                    .register_template_string("row", files.row)
+                    .context(RegisterSnafu { component: "row" })?;
+                (true, Some(files.css))
+            }
+            None => {
+                let index_path = config_dir.join(config.index_file);
+                let index = std::fs::read_to_string(&index_path).context(IoSnafu {
+                    component: "index",
+                    path: index_path,
+                })?;
+                registry
+                    .register_template_string("index", index)
+                    .context(RegisterSnafu { component: "index" })?;
+                let has_row = config.row_file.is_some();
+                if let Some(row_file) = config.row_file {
+                    let row_path = config_dir.join(row_file);
+                    let row = std::fs::read_to_string(&row_path).context(IoSnafu {
+                        component: "row",
+                        path: row_path,
+                    })?;
+                    registry
+                        .register_template_string("row", row)
+                        .context(RegisterSnafu { component: "row" })?;
+                }
+                (has_row, None)
+            }
+        };
+        let mut assets = match config.assets_dir {
+            Some(assets_dir) => {
+                let assets_path = config_dir.join(assets_dir);
+                Assets::load(&assets_path).context(IoSnafu {
+                    component: "assets",
+                    path: assets_path,
+                })?
+            }
+            None => Assets::default(),
+        };
+        if let Some(css) = css {
+            assets.insert("theme.css", css.as_bytes());
+        }
+        let mut help_prefixes = Vec::new();
+        for help in config.help {
+            let help_path = config_dir.join(help.file);
+            let content = std::fs::read_to_string(&help_path).context(IoSnafu {
+                component: "help",
+                path: help_path,
+            })?;
+            let prefix = normalize_prefix(&help.prefix);
+            registry
+                .register_template_string(&format!("help:{prefix}"), content)
+                .context(RegisterSnafu { component: "help" })?;
+            help_prefixes.push(prefix);
+        }
+        help_prefixes.sort_by_key(|prefix| std::cmp::Reverse(prefix.len()));
+        let maintenance = match config.maintenance_file {
+            Some(maintenance_file) => {
+                let maintenance_path = config_dir.join(maintenance_file);
+                std::fs::read_to_string(&maintenance_path).context(IoSnafu {
+                    component: "maintenance",
+                    path: maintenance_path,
+                })?
+            }
+            None => maintenance::DEFAULT_TEMPLATE.to_string(),
+        };
         registry
-            .register_template_string("index", index)
-            .context(RegisterSnafu { component: "index" })?;
-        registry.register_helper("from_mtimestamp", Box::new(from_mtimestamp_helper));
-        registry.register_helper("humanize_size", Box::new(humanize_size_helper));
-        Ok(Self { registry })
+            .register_template_string("maintenance", maintenance)
+            .context(RegisterSnafu {
+                component: "maintenance",
+            })?;
+        let timezone: Tz = config.timezone.parse().context(TimezoneSnafu {
+            name: config.timezone,
+        })?;
+        registry.register_helper(
+            "from_mtimestamp",
+            Box::new(FromMtimestampHelper {
+                timezone,
+                date_formats: config.date_formats,
+            }),
+        );
+        registry.register_helper(
+            "t",
+            Box::new(TranslateHelper {
+                strings: config.strings,
+            }),
+        );
+        registry.register_helper("is_image", Box::new(is_image));
+        registry.register_helper(
+            "humanize_size",
+            Box::new(HumanizeSizeHelper {
+                units: config.size_units,
+            }),
+        );
+        Ok(Self {
+            registry,
+            help_prefixes,
+            timezone,
+            locales: config.locales,
+            has_row,
+            stream_threshold: config.stream_threshold,
+            minify: config.minify,
+            assets,
+        })
+    }
+
+    // A built-in theme, with other options of [template] as defaults.
+    pub fn theme(theme: Theme) -> Result<Self, TemplateLoadError> {
+        let mut config: TemplateConfig =
+            serde_json::from_value(serde_json::json!({})).expect("all fields have defaults");
+        config.theme = Some(theme);
+        // Themes are not read from files, so the config path does not matter
+        Self::from_config(Path::new("config.toml"), config)
+    }
+
+    // Name of the help template for directory with given prefix (like "/debian/dists/"), if any.
+    fn help_for(&self, prefix: &str) -> Option<String> {
+        self.help_prefixes
+            .iter()
+            .find(|p| prefix.starts_with(p.as_str()))
+            .map(|p| format!("help:{p}"))
+    }
+
+    fn streams(&self, entries: usize) -> bool {
+        self.has_row && entries >= self.stream_threshold
+    }
+
+    // Renders "row" for an entry outside the page. Fields of the page are merged in, so that both
+    // "this" and "@root" work like within the page.
+    fn render_row(
+        &self,
+        entry: &DirEntryInfo,
+        page: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<String, RenderError> {
+        let mut data = serde_json::to_value(entry).map_err(RenderErrorReason::SerdeError)?;
+        if let Some(data) = data.as_object_mut() {
+            for (k, v) in page {
+                data.entry(k).or_insert_with(|| v.clone());
+            }
+        }
+        let html = self.registry.render("row", &data)?;
+        Ok(self.minified(html))
     }
 
     pub fn render<T>(&self, name: &str, data: &T) -> Result<String, RenderError>
     where
         T: Serialize,
     {
-        self.registry.render(name, data)
+        let html = self.registry.render(name, data)?;
+        Ok(self.minified(html))
+    }
+
+    fn minified(&self, html: String) -> String {
+        match self.minify {
+            true => minify::collapse_whitespace(&html),
+            false => html,
+        }
+    }
+}
+
+// Options parsed from strings, checked without side effects by both App::router and --dry-run.
+pub struct Settings {
+    real_ip_header: Option<HeaderName>,
+    // Without trailing slash
+    base_url: Option<Arc<str>>,
+    allowed_hosts: Option<Arc<AllowedHosts>>,
+}
+
+impl Settings {
+    pub fn parse(config: &ServiceConfig, network: &NetworkConfig) -> Result<Self, YadexError> {
+        let real_ip_header = network
+            .real_ip_header
+            .as_deref()
+            .map(HeaderName::try_from)
+            .transpose()
+            .whatever_context("invalid real_ip_header")?;
+        let base_url = config
+            .base_url
+            .as_deref()
+            .map(|url| {
+                let uri = url.parse::<Uri>().whatever_context("invalid base_url")?;
+                if uri.scheme().is_none() || uri.authority().is_none() {
+                    snafu::whatever!("base_url must be absolute, like \"https://example.com\"");
+                }
+                Ok(Arc::<str>::from(url.trim_end_matches('/')))
+            })
+            .transpose()?;
+        let allowed_hosts = match network.allowed_hosts.is_empty() {
+            true => None,
+            false => Some(Arc::new(AllowedHosts {
+                hosts: network
+                    .allowed_hosts
+                    .iter()
+                    .map(|h| h.to_ascii_lowercase())
+                    .collect(),
+                status: StatusCode::from_u16(network.disallowed_host_status)
+                    .whatever_context("invalid disallowed_host_status")?,
+                body: network.disallowed_host_body.clone(),
+            })),
+        };
+        Ok(Self {
+            real_ip_header,
+            base_url,
+            allowed_hosts,
+        })
     }
 }
 
 impl App {
-    pub async fn serve(
+    // Sets up an instance (and chroot if configured), returning the router to serve. Without
+    // connect_info (ConnectInfo of connections), clients are known by real_ip_header only.
+    pub fn router(
         config: ServiceConfig,
-        listener: TcpListener,
+        network: &NetworkConfig,
         template: Template,
-    ) -> Result<(), YadexError> {
-        let root: &'static Path = Box::leak(Box::<Path>::from(config.root));
+        components: Components,
+        connect_info: bool,
+    ) -> Result<Router, YadexError> {
+        let Settings {
+            real_ip_header,
+            base_url,
+            allowed_hosts,
+        } = Settings::parse(&config, network)?;
+        let Components {
+            mirrorz,
+            status,
+            geoip,
+            index,
+            redirects,
+            maintenance,
+            shedder,
+            audit,
+            user_agents,
+            hotlink,
+            #[cfg(feature = "thumbnails")]
+            thumbnails,
+        } = components;
+        // Otherwise all clients would share one throttle, and GeoIP would never match
+        if !connect_info && real_ip_header.is_none() {
+            if user_agents.as_ref().is_some_and(|rules| rules.throttles()) {
+                snafu::whatever!(
+                    "User-Agent rules throttling clients need ConnectInfo or real_ip_header"
+                );
+            }
+            if geoip.is_some() {
+                snafu::whatever!("GeoIP redirects need ConnectInfo or real_ip_header");
+            }
+        }
+        // Before chroot, as /etc/passwd and /etc/group are outside
+        let owners = config.show_owner.then(Owners::load);
+        let device = root_device(&config).whatever_context("failed to stat root")?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
         if config.security == crate::config::Security::Chroot {
-            chroot(root).whatever_context("failed to chroot")?;
+            chroot(&config.root).whatever_context("failed to chroot")?;
             set_current_dir("/").whatever_context("failed to cd into new root")?;
-        } else {
-            set_current_dir(root).whatever_context("failed to cd into given path")?;
         }
-        let mut router = Router::new();
-        if config.template_index {
-            router = router.fallback(get(directory_listing));
+        let root = fs_root(&config).whatever_context("failed to resolve root")?;
+        if let Some(status) = &status {
+            status.spawn_refresh();
+        }
+        if let Some(index) = &index {
+            index.spawn_scan();
+        }
+        if let Some(maintenance) = &maintenance {
+            maintenance.spawn_check();
+        }
+        if let Some(redirects) = &redirects {
+            redirects.spawn_reload();
         }
-        if config.json_api {
-            router = router.route("/api/files", post(api_directory_listing));
+        #[cfg(feature = "thumbnails")]
+        if let Some(thumbnails) = &thumbnails {
+            thumbnails.spawn_prune();
         }
-        let router = router.with_state(AppState {
-            limit: if config.limit == 0 {
-                usize::MAX
-            } else {
-                config.limit as usize
+        let cache_watch = config.cache_ttl > 0 && config.cache_watch;
+        // Shared by cache and live updates
+        let watcher = if cache_watch || config.live_updates {
+            Some(Arc::new(
+                DirWatcher::new(root.clone()).whatever_context("failed to create watcher")?,
+            ))
+        } else {
+            None
+        };
+        let cache = if config.cache_ttl > 0 {
+            let cache = Arc::new(ListingCache::new(
+                Duration::from_secs(config.cache_ttl),
+                config.cache_capacity,
+                watcher.clone().filter(|_| cache_watch),
+                |listing: &Listing| listing.entries.len(),
+            ));
+            cache.spawn_invalidation();
+            Some(cache)
+        } else {
+            None
+        };
+        let state = AppState {
+            root: root.clone().into(),
+            limit: to_limit(config.limit),
+            limits: prefix_limits(&config),
+            order: Orders {
+                dirs: config.dir_order,
+                files: config.file_order,
+            },
+            orders: {
+                let mut orders: Vec<_> = config
+                    .orders
+                    .into_iter()
+                    .map(|o| {
+                        let orders = Orders {
+                            dirs: o.dirs.unwrap_or(config.dir_order),
+                            files: o.files.unwrap_or(config.file_order),
+                        };
+                        (normalize_prefix(&o.prefix), orders)
+                    })
+                    .collect();
+                orders.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+                orders
             },
             template: Arc::new(template),
-        });
-        sd_notify::notify(true, &[sd_notify::NotifyState::Ready])
-            .whatever_context("failed to do systemd notify")?;
-        axum::serve(listener, router)
-            .await
-            .with_whatever_context(|_| "serve failed")
+            mirrorz: mirrorz.map(Arc::new),
+            status,
+            geoip: geoip.map(Arc::new),
+            real_ip_header,
+            hostname: hostname.into(),
+            redirects,
+            maintenance,
+            shedder,
+            audit,
+            user_agents,
+            hotlink,
+            base_url,
+            allowed_hosts,
+            dir_size: config.dir_size.then(|| {
+                Arc::new(DirSizeCache::new(
+                    Duration::from_secs(config.dir_size_ttl),
+                    config.dir_size_capacity,
+                    config.dir_size_concurrency,
+                    root.clone(),
+                    device,
+                ))
+            }),
+            owners: owners.map(Arc::new),
+            device,
+            cache,
+            live: watcher.filter(|_| config.live_updates),
+            index,
+            stat_concurrency: config.stat_concurrency.max(1),
+            fast_listing_threshold: config.fast_listing_threshold,
+            json_api: config.json_api,
+            markdown_preview: config.markdown_preview,
+            text_preview: config.text_preview,
+            #[cfg(feature = "thumbnails")]
+            thumbnails,
+            preview_max_size: config.preview_max_size,
+            compress: config.compress,
+            compressed: (config.compress && config.compressed_cache_capacity > 0)
+                .then(|| Arc::new(CompressedCache::new(config.compressed_cache_capacity))),
+            listing_memory_limit: match config.listing_memory_limit {
+                0 => usize::MAX,
+                limit => limit as usize,
+            },
+        };
+        let mut router = Router::new();
+        if config.template_index {
+            router = router.fallback(get(directory_listing).route_layer(
+                middleware::from_fn_with_state(state.clone(), geoip_redirect),
+            ));
+        }
+        // Unversioned paths are kept as aliases of v1. Breaking changes would go to a new version.
+        for prefix in ["/api/v1", "/api"] {
+            if config.json_api {
+                router = router.route(
+                    &format!("{prefix}/files"),
+                    post(api_directory_listing).get(api_directory_listing_get),
+                );
+            }
+            if state.index.is_some() {
+                router = router.route(&format!("{prefix}/search"), get(api_search));
+                router = router.route(&format!("{prefix}/recent"), get(api_recent));
+                router = router.route(&format!("{prefix}/du"), get(api_du));
+            }
+            if state.live.is_some() {
+                router = router.route(&format!("{prefix}/events"), get(api_events));
+            }
+            if config.json_api || state.index.is_some() || state.live.is_some() {
+                router = router.route(&format!("{prefix}/openapi.json"), get(openapi_document));
+            }
+        }
+        if config.template_index && !state.template.assets.is_empty() {
+            router = router.route("/_yadex/assets/{*path}", get(asset));
+        }
+        if state.mirrorz.is_some() {
+            router = router.route("/mirrorz.json", get(mirrorz_document));
+        }
+        let router = router
+            .layer(middleware::from_fn(negotiate_error))
+            // Inside set_vary, as responses under protected prefixes vary by Referer
+            .layer(middleware::from_fn_with_state(state.clone(), check_referer))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                maintenance_mode,
+            ))
+            .layer(middleware::from_fn(set_vary))
+            .layer(middleware::from_fn_with_state(state.clone(), map_redirect))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                check_user_agent,
+            ))
+            .layer(middleware::from_fn_with_state(state.clone(), check_host));
+        let router = match state.audit {
+            Some(_) => router.layer(middleware::from_fn_with_state(state.clone(), audit_log)),
+            None => router,
+        };
+        // Outermost, so that rejected requests are logged too
+        let router = match network.access_log {
+            true => router.layer(middleware::from_fn_with_state(state.clone(), access_log)),
+            false => router,
+        }
+        .with_state(state);
+        Ok(router)
+    }
+}
+
+// Where files under root are, as seen after chroot if any. Relative paths of handlers (like
+// "./debian/dists") are resolved against it, so that instances don't depend on current directory.
+pub fn fs_root(config: &ServiceConfig) -> std::io::Result<PathBuf> {
+    match config.security {
+        crate::config::Security::Chroot => Ok(PathBuf::from("/")),
+        _ => std::path::absolute(&config.root),
+    }
+}
+
+// Device of root, with one_filesystem. Before chroot, as root is given by its outside path.
+pub fn root_device(config: &ServiceConfig) -> std::io::Result<Option<u64>> {
+    config
+        .one_filesystem
+        .then(|| Ok(fs::metadata(&config.root)?.dev()))
+        .transpose()
+}
+
+// 0 for unlimited
+pub fn to_limit(limit: u64) -> usize {
+    match limit {
+        0 => usize::MAX,
+        limit => limit as usize,
     }
 }
 
+// Overrides of limit by prefix (like "/debian/pool/"), longest first.
+pub fn prefix_limits(config: &ServiceConfig) -> Vec<(String, usize)> {
+    let mut limits: Vec<_> = config
+        .limits
+        .iter()
+        .map(|l| (normalize_prefix(&l.prefix), to_limit(l.limit)))
+        .collect();
+    limits.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    limits
+}
+
+// Max number of entries listed in given directory (like "./debian/pool"), by the longest prefix
+// of limits (from prefix_limits) covering it.
+pub fn limit_for(limit: usize, limits: &[(String, usize)], path: &Path) -> usize {
+    let prefix = status_prefix(path);
+    limits
+        .iter()
+        .find(|(p, _)| prefix.starts_with(p.as_str()))
+        .map_or(limit, |(_, limit)| *limit)
+}
+
 #[derive(Clone)]
 pub struct AppState {
+    // Relative paths are resolved against it
+    root: Arc<Path>,
     limit: usize,
+    // Overrides of limit by prefix (like "/debian/pool/"), longest first
+    limits: Vec<(String, usize)>,
+    order: Orders,
+    // Overrides of order by prefix, longest first
+    orders: Vec<(String, Orders)>,
     template: Arc<Template>,
+    mirrorz: Option<Arc<Mirrorz>>,
+    status: Option<Arc<StatusStore>>,
+    geoip: Option<Arc<GeoRedirect>>,
+    real_ip_header: Option<HeaderName>,
+    hostname: Arc<str>,
+    redirects: Option<Arc<Redirects>>,
+    maintenance: Option<Arc<Maintenance>>,
+    shedder: Option<Arc<LoadShedder>>,
+    audit: Option<Arc<AuditLog>>,
+    user_agents: Option<Arc<UserAgentRules>>,
+    hotlink: Option<Arc<Hotlink>>,
+    // Without trailing slash
+    base_url: Option<Arc<str>>,
+    allowed_hosts: Option<Arc<AllowedHosts>>,
+    dir_size: Option<Arc<DirSizeCache>>,
+    owners: Option<Arc<Owners>>,
+    // Of root, with one_filesystem
+    device: Option<u64>,
+    cache: Option<Arc<ListingCache<Listing>>>,
+    // For live updates
+    live: Option<Arc<DirWatcher>>,
+    index: Option<Arc<FileIndex>>,
+    stat_concurrency: usize,
+    // 0 to disable
+    fast_listing_threshold: usize,
+    // Max bytes taken by a listing being built
+    listing_memory_limit: usize,
+    // Whether JSON is given for ?format=json
+    json_api: bool,
+    // Whether Markdown is rendered for ?view=rendered
+    markdown_preview: bool,
+    // Whether text files are highlighted for ?view=text
+    text_preview: bool,
+    preview_max_size: u64,
+    #[cfg(feature = "thumbnails")]
+    thumbnails: Option<Arc<Thumbnails>>,
+    // Whether pages are compressed for clients accepting it, and cached when compressed
+    compress: bool,
+    compressed: Option<Arc<CompressedCache>>,
+}
+
+impl AppState {
+    // Whether thumbnails are given for ?thumb
+    fn serves_thumbnails(&self) -> bool {
+        #[cfg(feature = "thumbnails")]
+        return self.thumbnails.is_some();
+        #[cfg(not(feature = "thumbnails"))]
+        false
+    }
+
+    // Max number of entries listed in given directory.
+    fn limit_for(&self, path: &Path) -> usize {
+        limit_for(self.limit, &self.limits, path)
+    }
+
+    // Samples of latency of listing I/O, for load shedding.
+    fn record_io(&self, start: std::time::Instant) {
+        if let Some(shedder) = &self.shedder {
+            shedder.record(start.elapsed());
+        }
+    }
+
+    async fn open_dir(&self, path: &Path) -> Result<tokio::fs::ReadDir, YadexError> {
+        let start = std::time::Instant::now();
+        let read_dir = tokio::fs::read_dir(self.root.join(path))
+            .await
+            .context(NotFoundSnafu)?;
+        self.record_io(start);
+        Ok(read_dir)
+    }
+
+    // Whether an entry is on the filesystem of root, always true without one_filesystem.
+    fn on_root_filesystem(&self, meta: &fs::Metadata) -> bool {
+        self.device.is_none_or(|device| meta.dev() == device)
+    }
+
+    // Paths on other filesystems than root's are not found, with one_filesystem.
+    async fn check_filesystem(&self, path: &Path) -> Result<(), YadexError> {
+        if self.device.is_some() {
+            let meta = tokio::fs::metadata(self.root.join(path))
+                .await
+                .context(NotFoundSnafu)?;
+            if !self.on_root_filesystem(&meta) {
+                return Err(YadexError::NotFound {
+                    source: std::io::ErrorKind::NotFound.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Orders of rows in given directory.
+    fn order_for(&self, path: &Path) -> Orders {
+        let prefix = status_prefix(path);
+        self.orders
+            .iter()
+            .find(|(p, _)| prefix.starts_with(p.as_str()))
+            .map_or(self.order, |(_, order)| *order)
+    }
+}
+
+// Address of the client, as told by reverse proxy if configured. None without ConnectInfo (like
+// the router embedded and served without it), unless real_ip_header gives one.
+fn client_ip(req: &Request, real_ip_header: Option<&HeaderName>) -> Option<IpAddr> {
+    real_ip_header
+        .and_then(|name| req.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        // For X-Forwarded-For, the last one is added by our proxy.
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| peer.ip())
+        })
+}
+
+struct AllowedHosts {
+    // Lowercase, like "mirrors.example.com" or "*.example.com"
+    hosts: Vec<String>,
+    status: StatusCode,
+    body: String,
+}
+
+impl AllowedHosts {
+    fn allows(&self, host: &str) -> bool {
+        host_matches(&self.hosts, host)
+    }
+}
+
+// Patterns are lowercase, like "mirrors.example.com" or "*.example.com".
+pub fn host_matches(patterns: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_prefix('*') {
+            // "*.example.com" is for subdomains only
+            Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+            None => host == *pattern,
+        })
+}
+
+// Host of the request without port, from the URI (HTTP/2) or Host header.
+fn request_host(req: &Request) -> Option<&str> {
+    if let Some(host) = req.uri().host() {
+        return Some(host.trim_start_matches('[').trim_end_matches(']'));
+    }
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    // "[::1]:8880" or "example.com:8880"
+    match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next(),
+        None => host.split(':').next(),
+    }
+}
+
+// Rejects requests for unknown hosts, like those by IP scanners or via DNS rebinding.
+async fn check_host(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(allowed) = &state.allowed_hosts {
+        let host = request_host(&req).unwrap_or_default();
+        if !allowed.allows(host) {
+            tracing::debug!("rejected request for host {host:?}");
+            let mut resp = (allowed.status, allowed.body.clone()).into_response();
+            resp.extensions_mut().insert(Audited {
+                event: "disallowed_host",
+                detail: host.to_string(),
+            });
+            return resp;
+        }
+    }
+    next.run(req).await
+}
+
+// Marks requests whose listings are given as plain text, by User-Agent rules.
+#[derive(Clone)]
+pub struct PlainListing;
+
+async fn check_user_agent(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(rules) = &state.user_agents else {
+        return next.run(req).await;
+    };
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let client = client_ip(&req, state.real_ip_header.as_ref());
+    let (mut resp, event) = match rules.check(user_agent, client) {
+        Verdict::Pass => return next.run(req).await,
+        Verdict::Plain => {
+            req.extensions_mut().insert(PlainListing);
+            return next.run(req).await;
+        }
+        Verdict::Block => (
+            (StatusCode::FORBIDDEN, "403 Forbidden").into_response(),
+            "user_agent_blocked",
+        ),
+        Verdict::Throttled(retry_after) => (
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "429 Too Many Requests",
+            )
+                .into_response(),
+            "user_agent_throttled",
+        ),
+    };
+    tracing::debug!("{event}: {client:?} with {user_agent:?}");
+    resp.extensions_mut().insert(Audited {
+        event,
+        detail: user_agent.to_string(),
+    });
+    resp
+}
+
+async fn check_referer(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(rules) = &state.hotlink else {
+        return next.run(req).await;
+    };
+    let path = urlencoding::decode(req.uri().path())
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| req.uri().path().to_string());
+    let referer = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok());
+    let host = request_host(&req).unwrap_or_default();
+    let mut resp = match rules.check(&path, host, referer) {
+        None => return next.run(req).await,
+        Some(hotlink::Verdict::Allow) => next.run(req).await,
+        Some(hotlink::Verdict::Deny(target)) => {
+            let mut resp = match target {
+                Some(target) => (StatusCode::FOUND, [(header::LOCATION, target)]).into_response(),
+                None => (StatusCode::FORBIDDEN, "403 Forbidden").into_response(),
+            };
+            resp.extensions_mut().insert(Audited {
+                event: "hotlink",
+                detail: referer.unwrap_or_default().to_string(),
+            });
+            resp
+        }
+    };
+    negotiated_by(&mut resp, header::REFERER);
+    resp
+}
+
+// Redirects by the map file, before anything else.
+async fn map_redirect(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(redirects) = &state.redirects
+        && let Some(mut target) = redirects.redirect(req.uri().path(), req.uri().query())
+    {
+        if target.starts_with('/')
+            && let Some(base) = &state.base_url
+        {
+            target.insert_str(0, base);
+        }
+        tracing::debug!("redirecting {} to {target}", req.uri());
+        return (redirects.status(), [(header::LOCATION, target)]).into_response();
+    }
+    next.run(req).await
+}
+
+#[derive(Serialize)]
+struct MaintenanceData<'a> {
+    // Decoded, like "/debian/dists/"
+    request_path: &'a str,
+    // Prefix in maintenance, like "/debian/". None if the whole mirror is.
+    prefix: Option<&'a str>,
+    message: Option<&'a str>,
+    // Seconds
+    retry_after: u64,
+    locale: &'a str,
+    version: &'static str,
+    hostname: &'a str,
+}
+
+// While in maintenance, everything but assets (which the page could use) is answered with 503.
+// Prefixes in maintenance are matched against request paths, so they don't cover the API.
+async fn maintenance_mode(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(maintenance) = &state.maintenance else {
+        return next.run(req).await;
+    };
+    if req.uri().path().starts_with("/_yadex/assets/") {
+        return next.run(req).await;
+    }
+    let path = urlencoding::decode(req.uri().path())
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| req.uri().path().to_string());
+    let Some(matched) = maintenance.matches(&path) else {
+        return next.run(req).await;
+    };
+    let headers = req.headers();
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    let locale = negotiate(
+        &state.template.locales,
+        None,
+        headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok()),
+    );
+    let mut resp = match error_format(accept, &path) {
+        ErrorFormat::Json => Json(serde_json::json!({
+            "error": "maintenance",
+            "message": matched.message.unwrap_or("503 Service Unavailable"),
+            "path": path,
+        }))
+        .into_response(),
+        ErrorFormat::Html => {
+            let data = MaintenanceData {
+                request_path: &path,
+                prefix: matched.prefix,
+                message: matched.message,
+                retry_after: maintenance.retry_after(),
+                locale,
+                version: env!("CARGO_PKG_VERSION"),
+                hostname: &state.hostname,
+            };
+            match state.template.render("maintenance", &data) {
+                Ok(html) => Html(html).into_response(),
+                Err(e) => {
+                    error!("failed to render maintenance page: {e}");
+                    "503 Service Unavailable".into_response()
+                }
+            }
+        }
+        ErrorFormat::Text => match matched.message {
+            Some(message) => format!("503 Service Unavailable: {message}").into_response(),
+            None => "503 Service Unavailable".into_response(),
+        },
+    };
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    let headers = resp.headers_mut();
+    headers.insert(header::RETRY_AFTER, maintenance.retry_after().into());
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    negotiated_by(&mut resp, header::ACCEPT);
+    if state.template.locales.len() > 1 {
+        negotiated_by(&mut resp, header::ACCEPT_LANGUAGE);
+    }
+    resp
+}
+
+async fn access_log(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let start = std::time::Instant::now();
+    let client = client_ip(&req, state.real_ip_header.as_ref());
+    let (method, uri, version) = (req.method().clone(), req.uri().clone(), req.version());
+    let resp = next.run(req).await;
+    let record = access::Record {
+        client,
+        method,
+        uri,
+        version,
+        status: resp.status(),
+        content_length: resp.body().size_hint().exact(),
+        start,
+    };
+    resp.map(|body| Body::new(access::LoggedBody::new(body, record)))
+}
+
+// Writes events attached to responses by inner handlers and middlewares, and attempts of traversal.
+async fn audit_log(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(audit) = state.audit.clone() else {
+        return next.run(req).await;
+    };
+    let client = client_ip(&req, state.real_ip_header.as_ref());
+    let host = request_host(&req).unwrap_or_default().to_string();
+    let (method, uri) = (req.method().clone(), req.uri().clone());
+    let mut resp = next.run(req).await;
+    let req = audit::Request {
+        client,
+        method: &method,
+        uri: &uri,
+        host: &host,
+    };
+    if audit::is_traversal(&uri) {
+        let traversal = Audited {
+            event: "path_traversal",
+            detail: String::new(),
+        };
+        audit.record(&req, resp.status(), &traversal);
+    }
+    if let Some(audited) = resp.extensions_mut().remove::<Audited>() {
+        audit.record(&req, resp.status(), &audited);
+    }
+    resp
+}
+
+async fn geoip_redirect(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(geoip) = &state.geoip
+        && let Some(ip) = client_ip(&req, state.real_ip_header.as_ref())
+        && let Some(target) = geoip.redirect(ip, req.uri().path(), req.uri().query())
+    {
+        tracing::debug!("redirecting {ip} to {target}");
+        return (StatusCode::FOUND, [(header::LOCATION, target)]).into_response();
+    }
+    next.run(req).await
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -144,22 +1143,116 @@ struct DirEntryInfo {
     name: String,
     is_dir: bool,
     size: u64,
+    // Like "1,234,567", for tooltips
+    size_exact: String,
     href: String,
     datetime: i64,
+    is_symlink: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync: Option<SyncInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<OwnerInfo>,
+    // Listed without stat, so size and datetime are unknown (0)
+    #[serde(skip)]
+    stat_skipped: bool,
 }
 
-pub async fn direntry_info(val: Result<DirEntry, io::Error>) -> Option<(DirEntry, fs::Metadata)> {
-    let val = val.ok()?;
-    // We need to get the actual metadata (not symlink metadata) here
-    let meta = tokio::fs::metadata(val.path()).await.ok()?;
-    Some((val, meta))
+pub async fn direntry_info(val: DirEntry) -> Option<(DirEntry, fs::Metadata, Option<PathBuf>)> {
+    // Metadata of what symlinks point to, so that linked directories are listed as directories with
+    // their own mtime. Dangling symlinks are listed by their own metadata, like nginx does.
+    let meta = match tokio::fs::metadata(val.path()).await {
+        Ok(meta) => meta,
+        Err(_) => tokio::fs::symlink_metadata(val.path()).await.ok()?,
+    };
+    // File type of DirEntry is what symlink_metadata gives, mostly without an extra syscall
+    let link_target = match val.file_type().await.ok()?.is_symlink() {
+        true => tokio::fs::read_link(val.path()).await.ok(),
+        false => None,
+    };
+    Some((val, meta, link_target))
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct IndexData<'a> {
     entry: &'a [DirEntryInfo],
     maybe_truncated: bool,
+    // Number of entries listed, even when they are streamed (with entry left empty)
+    count: usize,
+    // Number of all entries, if truncated
+    total: Option<usize>,
+    // Whether the directory has no (visible) entries
+    is_empty: bool,
     cwd: &'a str,
+    sync: Option<&'a SyncInfo>,
+    // Rendered help block for this directory
+    help: Option<String>,
+    // Rendered file for ?view=rendered or ?view=text, shown instead of entries
+    preview: Option<String>,
+    show_owner: bool,
+    // Whether /api/search is available
+    search: bool,
+    // Whether images have thumbnails at ?thumb=SIZE
+    thumbnails: bool,
+    // Whether /api/v1/events is available
+    live: bool,
+    // Entries are listed without stat, so size and datetime are not known
+    fast_listing: bool,
+    // IANA name, like "Asia/Shanghai"
+    timezone: &'a str,
+    // Like "zh-CN"
+    locale: &'a str,
+    // Where rows go in streamed pages, with entry left empty
+    stream_rows: Option<&'a str>,
+    // Like "https://mirrors.example.com", and the absolute URL of this page with it
+    base_url: Option<&'a str>,
+    canonical_url: Option<String>,
+    // Decoded, like "/debian/dists/"
+    request_path: &'a str,
+    // Of yadex
+    version: &'static str,
+    hostname: &'a str,
+    // Unix timestamp
+    generated_at: i64,
+}
+
+const STREAM_ROWS_MARKER: &str = "<!-- yadex:stream-rows -->";
+const STREAM_CHUNK_ROWS: usize = 256;
+
+// Sends the page around rows first, and rows in chunks rendered on demand, so that huge listings
+// are never rendered into a single string.
+// With compression, chunks are compressed as they are rendered, and the whole compressed page is
+// given to on_complete.
+fn stream_page(
+    template: Arc<Template>,
+    head: String,
+    tail: String,
+    page: serde_json::Map<String, serde_json::Value>,
+    entries: Vec<DirEntryInfo>,
+    compression: Option<(Encoding, Option<impl FnOnce(Bytes) + Send + 'static>)>,
+) -> Body {
+    let chunks = (0..entries.len())
+        .step_by(STREAM_CHUNK_ROWS)
+        .map(move |start| {
+            let end = (start + STREAM_CHUNK_ROWS).min(entries.len());
+            let mut chunk = String::new();
+            for entry in &entries[start..end] {
+                chunk.push_str(&template.render_row(entry, &page).inspect_err(|e| {
+                    error!("failed to render row of {}: {e}", entry.href);
+                })?);
+            }
+            Ok::<_, RenderError>(chunk)
+        });
+    let pieces = std::iter::once(Ok(head))
+        .chain(chunks)
+        .chain(std::iter::once(Ok(tail)));
+    match compression {
+        Some((encoding, on_complete)) => Body::from_stream(futures_util::stream::iter(
+            compress::compress_stream(pieces, encoding, on_complete),
+        )),
+        None => Body::from_stream(futures_util::stream::iter(pieces)),
+    }
 }
 
 fn to_relative(base: &Path, path: &str) -> PathBuf {
@@ -180,7 +1273,7 @@ fn to_relative(base: &Path, path: &str) -> PathBuf {
     safe_path
 }
 
-fn path_to_href(path: &Path) -> String {
+pub fn path_to_href(path: &Path) -> String {
     let mut segments = Vec::new();
     for comp in path.components() {
         if comp == std::path::Component::CurDir {
@@ -204,114 +1297,1347 @@ fn remove_first_component<P: AsRef<Path>>(path: P) -> PathBuf {
     }
 }
 
-async fn get_entries(
-    path: &Path,
-    limit: usize,
-    sort: bool,
-) -> Result<Vec<DirEntryInfo>, YadexError> {
-    let mut entries = ReadDirStream::new(tokio::fs::read_dir(path).await.context(NotFoundSnafu)?)
-        .take(limit)
-        .filter_map(async |entry| match direntry_info(entry).await {
-            Some((d, meta)) => {
-                let name = d.file_name();
-                let displayed_name = name.to_string_lossy();
-                if displayed_name.starts_with('.') {
-                    return None;
-                }
-                Some(DirEntryInfo {
-                    is_dir: meta.is_dir(),
-                    size: if meta.is_dir() { 0 } else { meta.size() },
-                    href: format!(
-                        "{href}{slash}",
-                        href = path_to_href(&path.join(d.file_name())),
-                        slash = if meta.is_dir() { "/" } else { "" }
-                    ),
-                    name: displayed_name.into_owned(),
-                    datetime: meta.mtime(),
-                })
-            }
-            None => None,
-        })
-        .collect::<Vec<_>>()
-        .await;
-    if sort {
-        entries.sort_by(|a, b| match (a.is_dir, b.is_dir) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
-        });
+// Owners and link targets are not recorded in the index.
+fn indexed_entry_info(path: &Path, entry: IndexedEntry) -> DirEntryInfo {
+    DirEntryInfo {
+        href: format!(
+            "{href}{slash}",
+            href = path_to_href(&path.join(&entry.name)),
+            slash = if entry.is_dir { "/" } else { "" }
+        ),
+        name: entry.name,
+        is_dir: entry.is_dir,
+        size: entry.size,
+        size_exact: exact_size(entry.size),
+        datetime: entry.mtime,
+        is_symlink: entry.is_symlink,
+        link_target: None,
+        sync: None,
+        owner: None,
+        stat_skipped: false,
     }
-    Ok(entries)
 }
 
-#[derive(Deserialize)]
-pub struct APIInput {
-    path: String,
+// Types from getdents (d_type) are used instead of stat. Only symlinks are followed, to tell directories,
+// and directories are stat-ed with device (of root, for one_filesystem) to find mount points.
+async fn fast_entry_info(path: &Path, d: DirEntry, device: Option<u64>) -> Option<DirEntryInfo> {
+    let file_type = d.file_type().await.ok()?;
+    let meta = match file_type.is_symlink() || device.is_some() && file_type.is_dir() {
+        true => tokio::fs::metadata(d.path()).await.ok(),
+        false => None,
+    };
+    if let (Some(device), Some(meta)) = (device, &meta)
+        && meta.dev() != device
+    {
+        return None;
+    }
+    // Dangling symlinks are listed as files, like with stat
+    let is_dir = meta.map_or(file_type.is_dir(), |meta| meta.is_dir());
+    let link_target = match file_type.is_symlink() {
+        true => tokio::fs::read_link(d.path()).await.ok(),
+        false => None,
+    };
+    Some(DirEntryInfo {
+        href: format!(
+            "{href}{slash}",
+            href = path_to_href(&path.join(d.file_name())),
+            slash = if is_dir { "/" } else { "" }
+        ),
+        name: d.file_name().to_string_lossy().into_owned(),
+        is_dir,
+        size: 0,
+        size_exact: exact_size(0),
+        datetime: 0,
+        is_symlink: link_target.is_some(),
+        link_target: link_target.map(|t| t.to_string_lossy().into_owned()),
+        sync: None,
+        owner: None,
+        stat_skipped: true,
+    })
 }
 
-#[derive(Serialize)]
-pub struct APIOutput {
-    entries: Vec<DirEntryInfo>,
-    maybe_truncated: bool,
+#[derive(Clone, Copy)]
+struct Orders {
+    dirs: Order,
+    files: Order,
 }
 
-#[axum::debug_handler]
-pub async fn api_directory_listing(
-    State(state): State<AppState>,
-    Json(payload): Json<APIInput>,
-) -> Result<Json<APIOutput>, YadexError> {
-    let mut path = payload.path;
-    if !path.ends_with('/') {
-        path.push('/');
-    }
-    let path = to_relative(Path::new("."), &path);
-    let path = path.as_path();
-    tracing::debug!("API listing directory: {:?}", path);
-
-    let entries = get_entries(path, state.limit, false).await?;
-    let maybe_truncated = entries.len() == state.limit;
-    let output = APIOutput {
-        entries,
-        maybe_truncated,
-    };
-    Ok(Json(output))
+// Directories first, then files, each in its own order. Ties (like entries listed without stat)
+// are ordered by name.
+fn sort_entries(entries: &mut [DirEntryInfo], orders: Orders) {
+    entries.sort_by(|a, b| match (a.is_dir, b.is_dir) {
+        (true, false) => std::cmp::Ordering::Less,
+        (false, true) => std::cmp::Ordering::Greater,
+        (is_dir, _) => {
+            let by_name = || a.name.to_lowercase().cmp(&b.name.to_lowercase());
+            match if is_dir { orders.dirs } else { orders.files } {
+                Order::Name => by_name(),
+                Order::Newest => b.datetime.cmp(&a.datetime).then_with(by_name),
+                Order::Oldest => a.datetime.cmp(&b.datetime).then_with(by_name),
+            }
+        }
+    });
 }
 
-#[axum::debug_handler]
-pub async fn directory_listing(
-    State(state): State<AppState>,
-    uri: Uri,
-) -> Result<Response, YadexError> {
-    let path = uri.path();
+#[derive(Clone)]
+struct Listing {
+    entries: Vec<DirEntryInfo>,
+    // Number of all entries, if stopped early by limit or memory budget
+    total: Option<usize>,
+}
 
-    // decode
-    let path = urlencoding::decode(path)
-        .map_err(|_| YadexError::NotFound {
-            source: std::io::ErrorKind::NotADirectory.into(),
-        })?
-        .into_owned();
+// Rough bytes taken by an entry in a listing, mostly by its name and href.
+fn entry_cost(path: &Path, name: &OsStr) -> usize {
+    size_of::<DirEntryInfo>() + path.as_os_str().len() + 2 * name.len()
+}
 
-    if !path.ends_with('/') {
-        return Ok(Redirect::permanent(&format!("{path}/")).into_response());
+// Large directories are listed from the index if it's up to date with them. Entries are sorted.
+async fn get_entries(state: &AppState, path: &Path) -> Result<Listing, YadexError> {
+    let limit = state.limit_for(path);
+    let budget = state.listing_memory_limit;
+    state.check_filesystem(path).await?;
+    if let Some(index) = &state.index {
+        let (index, indexed_path) = (index.clone(), path.to_path_buf());
+        let indexed = tokio::task::spawn_blocking(move || index.list(&indexed_path, limit, budget))
+            .await
+            .whatever_context("failed to list from index")?;
+        if let Some((indexed, total)) = indexed {
+            let mut entries: Vec<_> = indexed
+                .into_iter()
+                .map(|entry| indexed_entry_info(path, entry))
+                .collect();
+            sort_entries(&mut entries, state.order_for(path));
+            let total = total.map(|total| total as usize);
+            return Ok(Listing { entries, total });
+        }
     }
+    let mut read_dir = ReadDirStream::new(state.open_dir(path).await?);
+    let (mut dir_entries, mut read, mut visible, mut used) = (Vec::new(), 0, 0, 0);
+    let truncated = loop {
+        if read == limit {
+            break true;
+        }
+        let Some(entry) = read_dir.next().await else {
+            break false;
+        };
+        read += 1;
+        let Ok(d) = entry else {
+            continue;
+        };
+        let name = d.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        visible += 1;
+        used += entry_cost(path, &name);
+        if used > budget {
+            tracing::warn!("listing of {path:?} truncated, as it exceeds memory limit");
+            break true;
+        }
+        dir_entries.push(d);
+    };
+    // The rest is counted by names only, without stat or keeping them
+    let total = match truncated {
+        true => Some(
+            visible
+                + read_dir
+                    .filter(|entry| {
+                        std::future::ready(
+                            entry
+                                .as_ref()
+                                .is_ok_and(|d| !d.file_name().as_encoded_bytes().starts_with(b".")),
+                        )
+                    })
+                    .count()
+                    .await,
+        ),
+        false => None,
+    };
+    let mut entries = entries_info(state, path, dir_entries).await;
+    sort_entries(&mut entries, state.order_for(path));
+    Ok(Listing { entries, total })
+}
 
-    let path = to_relative(Path::new("."), &path);
-    let path = path.as_path();
-    tracing::debug!("listing directory: {:?}", path);
-
-    let entries = get_entries(path, state.limit, true).await?;
-    let html = state
+// Stats entries read from a directory, unless there are so many that fast listing applies.
+async fn entries_info(
+    state: &AppState,
+    path: &Path,
+    dir_entries: Vec<DirEntry>,
+) -> Vec<DirEntryInfo> {
+    let owners = state.owners.as_deref();
+    let fast =
+        state.fast_listing_threshold > 0 && dir_entries.len() >= state.fast_listing_threshold;
+    if fast {
+        futures_util::stream::iter(dir_entries)
+            .map(|d| fast_entry_info(path, d, state.device))
+            .buffer_unordered(state.stat_concurrency)
+            .filter_map(async |info| info)
+            .collect()
+            .await
+    } else {
+        futures_util::stream::iter(dir_entries)
+            // Stat in parallel, which matters on network filesystems. Each stat is sampled on its
+            // own, so latency is not hidden by the parallelism.
+            .map(async |d| {
+                let start = std::time::Instant::now();
+                let info = direntry_info(d).await;
+                state.record_io(start);
+                info
+            })
+            .buffer_unordered(state.stat_concurrency)
+            .filter_map(async |info| match info {
+                Some((_, meta, _)) if !state.on_root_filesystem(&meta) => None,
+                Some((d, meta, link_target)) => {
+                    let name = d.file_name();
+                    let displayed_name = name.to_string_lossy();
+                    let size = if meta.is_dir() { 0 } else { meta.size() };
+                    Some(DirEntryInfo {
+                        is_dir: meta.is_dir(),
+                        size,
+                        size_exact: exact_size(size),
+                        href: format!(
+                            "{href}{slash}",
+                            href = path_to_href(&path.join(d.file_name())),
+                            slash = if meta.is_dir() { "/" } else { "" }
+                        ),
+                        name: displayed_name.into_owned(),
+                        datetime: meta.mtime(),
+                        is_symlink: link_target.is_some(),
+                        link_target: link_target.map(|t| t.to_string_lossy().into_owned()),
+                        sync: None,
+                        owner: owners.map(|owners| owners.info(&meta)),
+                        stat_skipped: false,
+                    })
+                }
+                None => None,
+            })
+            .collect()
+            .await
+    }
+}
+
+struct Page {
+    entries: Vec<DirEntryInfo>,
+    // Number of all entries of the directory
+    total: usize,
+    // Last name of the page, if there are more
+    last: Option<String>,
+}
+
+// Entries of a page among all entries of a directory read in any order: the first page_size named
+// after the cursor are kept, and one more to know if there are more.
+struct PageWindow<T> {
+    after: Option<String>,
+    page_size: usize,
+    kept: BTreeMap<String, T>,
+    total: usize,
+}
+
+impl<T> PageWindow<T> {
+    fn new(after: Option<String>, page_size: usize) -> Self {
+        Self {
+            after,
+            page_size,
+            kept: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    fn push(&mut self, name: String, item: T) {
+        self.total += 1;
+        if self.after.as_ref().is_some_and(|after| name <= *after) {
+            return;
+        }
+        self.kept.insert(name, item);
+        if self.kept.len() > self.page_size + 1 {
+            self.kept.pop_last();
+        }
+    }
+
+    // Number of all entries, those of the page in name order, and the last name of the page if
+    // there are more.
+    fn finish(mut self) -> (usize, Vec<T>, Option<String>) {
+        let more = self.kept.len() > self.page_size;
+        if more {
+            self.kept.pop_last();
+        }
+        let last = more
+            .then(|| self.kept.keys().next_back().cloned())
+            .flatten();
+        (self.total, self.kept.into_values().collect(), last)
+    }
+}
+
+// A page of a directory in name order, reached through all of its entries (not only the first
+// limit of them). Only entries of the page are stat-ed, and limit applies to the page size instead.
+async fn get_page(
+    state: &AppState,
+    path: &Path,
+    after: Option<String>,
+    page_size: usize,
+) -> Result<Page, YadexError> {
+    let page_size = page_size.min(state.limit_for(path)).max(1);
+    state.check_filesystem(path).await?;
+    if let Some(index) = &state.index {
+        let (index, indexed_path, indexed_after) =
+            (index.clone(), path.to_path_buf(), after.clone());
+        let indexed = tokio::task::spawn_blocking(move || {
+            index.page(&indexed_path, indexed_after.as_deref(), page_size)
+        })
+        .await
+        .whatever_context("failed to list from index")?;
+        if let Some((indexed, more, total)) = indexed {
+            let entries: Vec<_> = indexed
+                .into_iter()
+                .map(|entry| indexed_entry_info(path, entry))
+                .collect();
+            let last = more
+                .then(|| entries.last().map(|e| e.name.clone()))
+                .flatten();
+            let total = total as usize;
+            return Ok(Page {
+                entries,
+                total,
+                last,
+            });
+        }
+    }
+    let mut read_dir = ReadDirStream::new(state.open_dir(path).await?);
+    let mut window = PageWindow::new(after, page_size);
+    while let Some(entry) = read_dir.next().await {
+        let Ok(d) = entry else {
+            continue;
+        };
+        let name = d.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') {
+            window.push(name, d);
+        }
+    }
+    let (total, dir_entries, last) = window.finish();
+    let mut entries = entries_info(state, path, dir_entries).await;
+    entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(Page {
+        entries,
+        total,
+        last,
+    })
+}
+
+// Like get_entries, but from cache if enabled.
+async fn list_entries(state: &AppState, path: &Path) -> Result<Listing, YadexError> {
+    let Some(cache) = &state.cache else {
+        return read_entries(state, path).await;
+    };
+    if let Some(listing) = cache.get(path) {
+        return Ok(listing.as_ref().clone());
+    }
+    let generation = cache.prepare(path);
+    let listing = read_entries(state, path).await?;
+    cache.insert(path, generation, Arc::new(listing.clone()));
+    Ok(listing)
+}
+
+// Like get_entries, but shedding load while storage is saturated if enabled.
+async fn read_entries(state: &AppState, path: &Path) -> Result<Listing, YadexError> {
+    let Some(shedder) = &state.shedder else {
+        return get_entries(state, path).await;
+    };
+    let _admission = admit(shedder, path).await?;
+    get_entries(state, path).await
+}
+
+// Like get_page, but shedding load like read_entries.
+async fn read_page(
+    state: &AppState,
+    path: &Path,
+    after: Option<String>,
+    page_size: usize,
+) -> Result<Page, YadexError> {
+    let Some(shedder) = &state.shedder else {
+        return get_page(state, path, after, page_size).await;
+    };
+    let _admission = admit(shedder, path).await?;
+    get_page(state, path, after, page_size).await
+}
+
+async fn admit<'a>(shedder: &'a LoadShedder, path: &Path) -> Result<Admission<'a>, YadexError> {
+    shedder.admit().await.ok_or_else(|| {
+        tracing::debug!("listing of {path:?} rejected, as storage is saturated");
+        YadexError::Overloaded {
+            retry_after: shedder.retry_after(),
+        }
+    })
+}
+
+// Directory sizes not computed yet are left as 0.
+fn attach_dir_sizes(entries: &mut [DirEntryInfo], path: &Path, dir_size: &Arc<DirSizeCache>) {
+    for entry in entries.iter_mut().filter(|e| e.is_dir) {
+        let size = dir_size.get(&path.join(&entry.name)).unwrap_or(0);
+        entry.size = size;
+        entry.size_exact = exact_size(size);
+    }
+}
+
+// Like "/debian/dists/", as keys of StatusMap.
+fn status_prefix(path: &Path) -> String {
+    let cwd = remove_first_component(path);
+    if cwd == Path::new(".") {
+        "/".to_string()
+    } else {
+        format!("/{}/", cwd.display())
+    }
+}
+
+// Gives directories with a status of their own the status, and returns status of current directory.
+fn attach_sync_status(
+    entries: &mut [DirEntryInfo],
+    path: &Path,
+    status: &StatusStore,
+) -> Option<SyncInfo> {
+    let map = status.snapshot();
+    let prefix = status_prefix(path);
+    for entry in entries.iter_mut().filter(|e| e.is_dir) {
+        entry.sync = map.get(&format!("{prefix}{}/", entry.name)).cloned();
+    }
+    longest_prefix(&map, &prefix).cloned()
+}
+
+#[derive(Deserialize)]
+pub struct APIInput {
+    path: String,
+    // Continues after the last entry of previous page, as given by its next_cursor
+    cursor: Option<String>,
+    // Paginates with this many entries per page
+    page_size: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct APIOutput {
+    entries: Vec<DirEntryInfo>,
+    maybe_truncated: bool,
+    // Number of all entries, if truncated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    fast_listing: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync: Option<SyncInfo>,
+    // Absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+// Pages are ordered by name, and a cursor is the last name of a page. So iterating is stable even
+// when entries are added or removed between pages.
+fn decode_cursor(cursor: &str) -> Result<String, YadexError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|name| String::from_utf8(name).ok())
+        .ok_or(YadexError::InvalidCursor)
+}
+
+fn encode_cursor(name: &str) -> String {
+    URL_SAFE_NO_PAD.encode(name)
+}
+
+// FNV-1a of parts (each prefixed by its length), as ETags must stay the same across builds and
+// restarts, unlike hashes of DefaultHasher.
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(*part) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+// Strong ETag by hash of the body.
+fn body_etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", stable_hash(&[body]))
+}
+
+// ETag of a JSON listing, by what its output depends on: the directory (its mtime changes as entries
+// are added, removed or renamed), the page asked for, limit and sync status. So that polling
+// clients get 304 without listing the directory. Files modified in place (not replaced by rename,
+// like rsync does) are not noticed, and there's none with dir_size, as sizes change without mtime.
+async fn listing_etag(state: &AppState, input: &APIInput) -> Option<String> {
+    if state.dir_size.is_some() {
+        return None;
+    }
+    let path = api_path(&input.path);
+    let meta = tokio::fs::metadata(state.root.join(&path)).await.ok()?;
+    let generation = state
+        .status
+        .as_ref()
+        .map_or(0, |status| status.generation());
+    let hash = stable_hash(&[
+        path.as_os_str().as_encoded_bytes(),
+        &meta.ino().to_le_bytes(),
+        &meta.mtime().to_le_bytes(),
+        &meta.mtime_nsec().to_le_bytes(),
+        &(state.limit_for(&path) as u64).to_le_bytes(),
+        input.cursor.as_deref().unwrap_or_default().as_bytes(),
+        &input
+            .page_size
+            .map_or(0, |size| size as u64 + 1)
+            .to_le_bytes(),
+        &generation.to_le_bytes(),
+    ]);
+    Some(format!("\"{hash:016x}\""))
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+}
+
+// Whether If-Modified-Since is at or after mtime (in seconds), which is ignored with If-None-Match
+// like HTTP says.
+fn not_modified_since(headers: &HeaderMap, mtime: i64) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+        .is_some_and(|since| mtime <= since.timestamp())
+}
+
+// Like "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(timestamp: i64) -> Option<HeaderValue> {
+    chrono::DateTime::from_timestamp(timestamp, 0)?
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+        .try_into()
+        .ok()
+}
+
+// With ETag, so that clients polling with If-None-Match get 304 when nothing changed. It's
+// checked before listing, so that such 304s are cheap.
+async fn json_listing(
+    state: &AppState,
+    headers: &HeaderMap,
+    input: APIInput,
+    conditional: bool,
+) -> Result<Response, YadexError> {
+    let etag = listing_etag(state, &input).await;
+    if let Some(etag) = etag.as_deref()
+        && conditional
+        && etag_matches(headers, etag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let output = api_listing(state, input).await?;
+    let body = serde_json::to_vec(&output).whatever_context("failed to serialize response")?;
+    let mut resp = ([(header::CONTENT_TYPE, "application/json")], body).into_response();
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        resp.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(resp)
+}
+
+// Same as POST, with the body given as query, and If-None-Match honored.
+#[axum::debug_handler]
+pub async fn api_directory_listing_get(
+    State(state): State<AppState>,
+    Query(query): Query<APIInput>,
+    headers: HeaderMap,
+) -> Result<Response, YadexError> {
+    json_listing(&state, &headers, query, true).await
+}
+
+#[axum::debug_handler]
+pub async fn api_directory_listing(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<APIInput>,
+) -> Result<Response, YadexError> {
+    json_listing(&state, &headers, payload, false).await
+}
+
+// "/debian" -> "./debian/"
+fn api_path(path: &str) -> PathBuf {
+    let mut path = path.to_string();
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    to_relative(Path::new("."), &path)
+}
+
+async fn api_listing(state: &AppState, payload: APIInput) -> Result<APIOutput, YadexError> {
+    let path = api_path(&payload.path);
+    let path = path.as_path();
+    tracing::debug!("API listing directory: {:?}", path);
+
+    // Pages reach all entries, so they are never truncated, and total is always given
+    let (mut entries, total, truncated, next_cursor) = match (&payload.cursor, payload.page_size) {
+        (None, None) => {
+            let Listing { entries, total } = list_entries(state, path).await?;
+            (entries, total, total.is_some(), None)
+        }
+        (cursor, page_size) => {
+            let after = cursor.as_deref().map(decode_cursor).transpose()?;
+            let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+            let page = read_page(state, path, after, page_size).await?;
+            let next_cursor = page.last.as_deref().map(encode_cursor);
+            (page.entries, Some(page.total), false, next_cursor)
+        }
+    };
+    if let Some(dir_size) = &state.dir_size {
+        attach_dir_sizes(&mut entries, path, dir_size);
+    }
+    let sync = state
+        .status
+        .as_ref()
+        .and_then(|status| attach_sync_status(&mut entries, path, status));
+    let output = APIOutput {
+        fast_listing: entries.first().is_some_and(|e| e.stat_skipped),
+        entries,
+        maybe_truncated: truncated,
+        total,
+        sync,
+        next_cursor,
+    };
+    Ok(output)
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    // Like "/debian/dists/bookworm"
+    path: String,
+    #[serde(flatten)]
+    entry: DirEntryInfo,
+}
+
+fn search_hit((parent, entry): (String, IndexedEntry)) -> SearchHit {
+    let parent = Path::new(".").join(parent);
+    let entry = indexed_entry_info(&parent, entry);
+    SearchHit {
+        path: path_to_href(&parent.join(&entry.name)),
+        entry,
+    }
+}
+
+// Also for recent files
+#[derive(Serialize)]
+pub struct SearchOutput {
+    results: Vec<SearchHit>,
+    maybe_truncated: bool,
+}
+
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+// Even if limit in [service] is unlimited
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+#[axum::debug_handler]
+pub async fn api_search(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchOutput>, YadexError> {
+    let index = state
+        .index
+        .clone()
+        .expect("route is only registered with index enabled");
+    let q = query.q.trim().to_string();
+    if q.is_empty() {
+        return Ok(Json(SearchOutput {
+            results: Vec::new(),
+            maybe_truncated: false,
+        }));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(state.limit)
+        .min(MAX_SEARCH_LIMIT);
+    tracing::debug!("searching for {q:?}");
+    let (hits, maybe_truncated) = index
+        .query(move |index| index.search(&q, limit))
+        .await
+        .whatever_context("search panicked")?
+        .map_err(|e| color_eyre::eyre::eyre!(e))
+        .whatever_context("failed to search index")?;
+    let results = hits.into_iter().map(search_hit).collect();
+    Ok(Json(SearchOutput {
+        results,
+        maybe_truncated,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct EventData {
+    name: String,
+}
+
+// Pushes changes of entries in a directory, as events named "add", "remove" and "modify"
+// with the name of the entry, or "reset" when some changes might have been missed.
+#[axum::debug_handler]
+pub async fn api_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Response, YadexError> {
+    let watcher = state
+        .live
+        .clone()
+        .expect("route is only registered with live updates enabled");
+    let path = to_relative(Path::new("."), &query.path);
+    let meta = tokio::fs::metadata(state.root.join(&path))
+        .await
+        .context(NotFoundSnafu)?;
+    if !meta.is_dir() || !state.on_root_filesystem(&meta) {
+        return Err(YadexError::NotFound {
+            source: std::io::ErrorKind::NotADirectory.into(),
+        });
+    }
+    tracing::debug!("live updates of {:?}", path);
+    // Subscribe before watching, so that no change is missed
+    let changes = watcher.subscribe_entries();
+    let guard = watcher.watch_live(&path);
+    let events = futures_util::stream::unfold(
+        (changes, guard, path),
+        |(mut changes, guard, path)| async move {
+            let event = loop {
+                match changes.recv().await {
+                    Ok(change) if change.dir != path || change.name.starts_with('.') => continue,
+                    Ok(change) => {
+                        break Event::default()
+                            .event(change.kind.as_str())
+                            .json_data(EventData { name: change.name })
+                            .ok()?;
+                    }
+                    Err(RecvError::Lagged(_)) => break Event::default().event("reset").data(""),
+                    Err(RecvError::Closed) => return None,
+                }
+            };
+            Some((
+                Ok::<_, std::convert::Infallible>(event),
+                (changes, guard, path),
+            ))
+        },
+    );
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct RecentQuery {
+    // Defaults to the whole tree
+    prefix: Option<String>,
+    // Like "24h"
+    since: String,
+    limit: Option<usize>,
+}
+
+// "90", "90s", "30m", "24h", "7d" to seconds
+fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    value.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[axum::debug_handler]
+pub async fn api_recent(
+    State(state): State<AppState>,
+    Query(query): Query<RecentQuery>,
+) -> Result<Json<SearchOutput>, YadexError> {
+    let index = state
+        .index
+        .clone()
+        .expect("route is only registered with index enabled");
+    let since = parse_duration(&query.since).ok_or(YadexError::InvalidSince)?;
+    let since = chrono::Utc::now()
+        .timestamp()
+        .saturating_sub(since.try_into().unwrap_or(i64::MAX));
+    let path = to_relative(Path::new("."), query.prefix.as_deref().unwrap_or("/"));
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(state.limit)
+        .min(MAX_SEARCH_LIMIT);
+    tracing::debug!("recent files under {path:?} since {since}");
+    let (hits, maybe_truncated) = index
+        .query(move |index| index.recent(&path, since, limit))
+        .await
+        .whatever_context("listing recent files panicked")?
+        .map_err(|e| color_eyre::eyre::eyre!(e))
+        .whatever_context("failed to list recent files from index")?;
+    Ok(Json(SearchOutput {
+        results: hits.into_iter().map(search_hit).collect(),
+        maybe_truncated,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DuQuery {
+    // Defaults to the whole tree
+    prefix: Option<String>,
+    // Levels of subdirectories to show, 0 for the prefix only
+    #[serde(default = "default_du_depth")]
+    depth: usize,
+}
+
+fn default_du_depth() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+struct DuEntry {
+    // Like "/ubuntu/dists/"
+    path: String,
+    size: u64,
+    size_exact: String,
+    files: u64,
+}
+
+#[derive(Serialize)]
+pub struct DuOutput {
+    results: Vec<DuEntry>,
+}
+
+#[axum::debug_handler]
+pub async fn api_du(
+    State(state): State<AppState>,
+    Query(query): Query<DuQuery>,
+) -> Result<Json<DuOutput>, YadexError> {
+    let index = state
+        .index
+        .clone()
+        .expect("route is only registered with index enabled");
+    let path = to_relative(Path::new("."), query.prefix.as_deref().unwrap_or("/"));
+    tracing::debug!("disk usage of {path:?}, depth {}", query.depth);
+    let totals = index
+        .query(move |index| index.du(&path, query.depth))
+        .await
+        .whatever_context("summarizing disk usage panicked")?
+        .map_err(|e| color_eyre::eyre::eyre!(e))
+        .whatever_context("failed to summarize disk usage from index")?;
+    let results = totals
+        .into_iter()
+        .map(|(dir, size, files)| {
+            let href = path_to_href(&Path::new(".").join(dir));
+            DuEntry {
+                path: match href.as_str() {
+                    "/" => href,
+                    _ => format!("{href}/"),
+                },
+                size,
+                size_exact: exact_size(size),
+                files,
+            }
+        })
+        .collect();
+    Ok(Json(DuOutput { results }))
+}
+
+// Hand-written, to be kept in sync with the API.
+const OPENAPI_DOCUMENT: &str = include_str!("openapi.json");
+
+pub async fn openapi_document() -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        OPENAPI_DOCUMENT,
+    )
+        .into_response()
+}
+
+pub async fn asset(
+    State(state): State<AppState>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, YadexError> {
+    let asset = state
         .template
-        .render(
-            "index",
-            &IndexData {
-                entry: &entries,
-                maybe_truncated: entries.len() == state.limit,
-                cwd: remove_first_component(path).display().to_string().as_str(),
-            },
+        .assets
+        .get(&path)
+        .ok_or(YadexError::NotFound {
+            source: std::io::ErrorKind::NotFound.into(),
+        })?;
+    let etag = body_etag(&asset.content);
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, asset.content_type.to_string()),
+            (header::CACHE_CONTROL, "public, max-age=3600".to_string()),
+            (header::ETAG, etag),
+        ],
+        asset.content.clone(),
+    )
+        .into_response())
+}
+
+#[axum::debug_handler]
+pub async fn mirrorz_document(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let mirrorz = state
+        .mirrorz
+        .expect("route is only registered with mirrorz enabled");
+    Json(mirrorz.document())
+}
+
+// One name per line, with "/" after directories.
+fn plain_listing(entries: &[DirEntryInfo], total: Option<usize>) -> Response {
+    let mut text = String::with_capacity(entries.len() * 32);
+    for entry in entries {
+        text.push_str(&entry.name);
+        if entry.is_dir {
+            text.push('/');
+        }
+        text.push('\n');
+    }
+    if let Some(total) = total {
+        text.push_str(&format!("# truncated, {total} entries in total\n"));
+    }
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ListingQuery {
+    // Overrides Accept-Language
+    hl: Option<String>,
+    // "json" for the same as /api/v1/files
+    format: Option<String>,
+    cursor: Option<String>,
+    page_size: Option<usize>,
+    // "rendered" for Markdown files rendered inside the template, "text" for highlighted text files
+    view: Option<String>,
+    // Width and height in pixels that thumbnails of images fit in
+    #[cfg(feature = "thumbnails")]
+    thumb: Option<u32>,
+}
+
+#[axum::debug_handler]
+pub async fn directory_listing(
+    State(state): State<AppState>,
+    Query(query): Query<ListingQuery>,
+    plain: Option<Extension<PlainListing>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, YadexError> {
+    let path = uri.path();
+
+    // decode
+    let path = urlencoding::decode(path)
+        .map_err(|_| YadexError::NotFound {
+            source: std::io::ErrorKind::NotADirectory.into(),
+        })?
+        .into_owned();
+
+    let view = query.view.as_deref().and_then(View::from_query);
+    let previewable = match view {
+        Some(View::Rendered) => state.markdown_preview && preview::is_markdown(Path::new(&path)),
+        Some(View::Text) => state.text_preview && !path.ends_with('/'),
+        None => false,
+    };
+    if let Some(view) = view.filter(|_| previewable) {
+        return preview_file(&state, &query, &headers, &uri, &path, view).await;
+    }
+
+    #[cfg(feature = "thumbnails")]
+    if let (Some(size), Some(thumbnails)) = (query.thumb, &state.thumbnails)
+        && !path.ends_with('/')
+    {
+        return thumbnail(&state, thumbnails, &headers, &uri, &path, size).await;
+    }
+
+    if !path.ends_with('/') {
+        let query = uri.query().map(|q| format!("?{q}")).unwrap_or_default();
+        let base = state.base_url.as_deref().unwrap_or_default();
+        return Ok(Redirect::permanent(&format!("{base}{}/{query}", uri.path())).into_response());
+    }
+
+    if state.json_api && query.format.as_deref() == Some("json") && plain.is_none() {
+        let input = APIInput {
+            path,
+            cursor: query.cursor,
+            page_size: query.page_size,
+        };
+        return json_listing(&state, &headers, input, true).await;
+    }
+
+    let request_path = path;
+    let path = to_relative(Path::new("."), &request_path);
+    let path = path.as_path();
+    tracing::debug!("listing directory: {:?}", path);
+
+    let meta = tokio::fs::metadata(state.root.join(path)).await.ok();
+    if meta
+        .as_ref()
+        .is_some_and(|meta| !state.on_root_filesystem(meta))
+    {
+        return Err(YadexError::NotFound {
+            source: std::io::ErrorKind::NotFound.into(),
+        });
+    }
+
+    let Listing { mut entries, total } = list_entries(&state, path).await?;
+    // Latest mtime of the directory (changed as entries are added, removed or renamed) and of its
+    // entries (for files modified in place, and previews). Sync status and directory sizes change
+    // without any of them, so pages showing them have no Last-Modified.
+    let mtime = meta.map(|meta| {
+        entries
+            .iter()
+            .map(|e| e.datetime)
+            .fold(meta.mtime(), i64::max)
+    });
+    let not_modified = |last_modified: &HeaderValue| {
+        (
+            StatusCode::NOT_MODIFIED,
+            [(header::LAST_MODIFIED, last_modified.clone())],
         )
+            .into_response()
+    };
+    if plain.is_some() {
+        let last_modified = mtime.and_then(http_date);
+        if let (Some(mtime), Some(last_modified)) = (mtime, &last_modified)
+            && not_modified_since(&headers, mtime)
+        {
+            return Ok(not_modified(last_modified));
+        }
+        let mut resp = plain_listing(&entries, total);
+        if let Some(last_modified) = last_modified {
+            resp.headers_mut()
+                .insert(header::LAST_MODIFIED, last_modified);
+        }
+        return Ok(resp);
+    }
+    let mtime = mtime.filter(|_| state.status.is_none() && state.dir_size.is_none());
+    let last_modified = mtime.and_then(http_date);
+    if let (Some(mtime), Some(last_modified)) = (mtime, &last_modified)
+        && not_modified_since(&headers, mtime)
+    {
+        return Ok(not_modified(last_modified));
+    }
+    if let Some(dir_size) = &state.dir_size {
+        attach_dir_sizes(&mut entries, path, dir_size);
+    }
+    let sync = state
+        .status
+        .as_ref()
+        .and_then(|status| attach_sync_status(&mut entries, path, status));
+    let cwd = remove_first_component(path).display().to_string();
+    let mut data = IndexData {
+        entry: &entries,
+        maybe_truncated: total.is_some(),
+        count: entries.len(),
+        total,
+        is_empty: entries.is_empty(),
+        cwd: &cwd,
+        sync: sync.as_ref(),
+        help: None,
+        preview: None,
+        show_owner: state.owners.is_some(),
+        search: state.index.is_some(),
+        thumbnails: state.serves_thumbnails(),
+        live: state.live.is_some(),
+        fast_listing: entries.first().is_some_and(|e| e.stat_skipped),
+        timezone: state.template.timezone.name(),
+        locale: negotiate(
+            &state.template.locales,
+            query.hl.as_deref(),
+            headers
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|v| v.to_str().ok()),
+        ),
+        stream_rows: None,
+        base_url: state.base_url.as_deref(),
+        canonical_url: state
+            .base_url
+            .as_deref()
+            .map(|base| format!("{base}{}", uri.path())),
+        request_path: &request_path,
+        version: env!("CARGO_PKG_VERSION"),
+        hostname: &state.hostname,
+        generated_at: chrono::Utc::now().timestamp(),
+    };
+    if let Some(help) = state.template.help_for(&status_prefix(path)) {
+        data.help = Some(
+            state
+                .template
+                .render(&help, &data)
+                .context(RenderSnafu { template: "help" })?,
+        );
+    }
+    let encoding = state
+        .compress
+        .then(|| {
+            Encoding::negotiate(
+                headers
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|v| v.to_str().ok()),
+            )
+        })
+        .flatten();
+    // Compressed pages are cached by what they are rendered from, so that hits skip rendering
+    let cached = match (encoding, &state.compressed) {
+        (Some(encoding), Some(cache)) => {
+            let etag = page_etag(&mut data);
+            if let Some(page) = cache.get(&request_path, encoding, etag) {
+                return Ok(page_response(
+                    &state,
+                    &query,
+                    page.into(),
+                    Some(encoding),
+                    last_modified,
+                ));
+            }
+            Some((cache.clone(), etag))
+        }
+        _ => None,
+    };
+    if state.template.streams(entries.len()) {
+        data.entry = &[];
+        data.stream_rows = Some(STREAM_ROWS_MARKER);
+        let html = state
+            .template
+            .render("index", &data)
+            .context(RenderSnafu { template: "index" })?;
+        if let Some((head, tail)) = html.split_once(STREAM_ROWS_MARKER) {
+            let mut page = match serde_json::to_value(&data) {
+                Ok(serde_json::Value::Object(page)) => page,
+                _ => unreachable!("IndexData is always an object"),
+            };
+            // Rendered already, and could be large
+            page.remove("help");
+            let (head, tail) = (head.to_string(), tail.to_string());
+            let compression = encoding.map(|encoding| {
+                let on_complete = cached.map(|(cache, etag)| {
+                    let path = request_path.clone();
+                    move |page| cache.insert(path, encoding, etag, page)
+                });
+                (encoding, on_complete)
+            });
+            let body = stream_page(
+                state.template.clone(),
+                head,
+                tail,
+                page,
+                entries,
+                compression,
+            );
+            return Ok(page_response(&state, &query, body, encoding, last_modified));
+        }
+        // Template does not render {{{stream_rows}}}, so render as usual
+        data.entry = &entries;
+        data.stream_rows = None;
+    }
+    let html = state
+        .template
+        .render("index", &data)
+        .context(RenderSnafu { template: "index" })?;
+    let body = match encoding {
+        Some(encoding) => {
+            let page = compress::compress(encoding, html.as_bytes());
+            if let Some((cache, etag)) = cached {
+                cache.insert(request_path, encoding, etag, page.clone());
+            }
+            page.into()
+        }
+        None => html.into(),
+    };
+    Ok(page_response(&state, &query, body, encoding, last_modified))
+}
+
+// Of what a page is rendered from, except time of rendering, to find compressed pages rendered
+// from the same.
+fn page_etag(data: &mut IndexData) -> u64 {
+    // Feeds serialized data into the hasher, without buffering it
+    struct HashWriter(DefaultHasher);
+    impl std::io::Write for HashWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let generated_at = std::mem::take(&mut data.generated_at);
+    let mut writer = HashWriter(DefaultHasher::new());
+    serde_json::to_writer(&mut writer, data).expect("IndexData is always serializable");
+    data.generated_at = generated_at;
+    writer.0.finish()
+}
+
+// Vary is set for pages that could be compressed, whether compressed or not.
+fn page_response(
+    state: &AppState,
+    query: &ListingQuery,
+    body: Body,
+    encoding: Option<Encoding>,
+    last_modified: Option<HeaderValue>,
+) -> Response {
+    let mut resp = ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], body).into_response();
+    if let Some(last_modified) = last_modified {
+        resp.headers_mut()
+            .insert(header::LAST_MODIFIED, last_modified);
+    }
+    if let Some(encoding) = encoding {
+        resp.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+    if state.compress {
+        negotiated_by(&mut resp, header::ACCEPT_ENCODING);
+    }
+    translated(state, query, resp)
+}
+
+// Files too large to preview, or binary ones as text, are redirected to as-is.
+async fn preview_file(
+    state: &AppState,
+    query: &ListingQuery,
+    headers: &HeaderMap,
+    uri: &Uri,
+    request_path: &str,
+    view: View,
+) -> Result<Response, YadexError> {
+    let path = to_relative(Path::new("."), request_path);
+    // Hidden like in listings
+    if path
+        .file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    {
+        return Err(YadexError::NotFound {
+            source: std::io::ErrorKind::NotFound.into(),
+        });
+    }
+    state.check_filesystem(&path).await?;
+    let max_size = state.preview_max_size;
+    let (read_path, root) = (path.clone(), state.root.clone());
+    let html = tokio::task::spawn_blocking(move || {
+        preview::read_capped(&root.join(&read_path), max_size)
+            .map(|content| content.and_then(|content| view.render(&read_path, &content)))
+    })
+    .await
+    .whatever_context("previewing file panicked")?
+    .context(NotFoundSnafu)?;
+    let Some(html) = html else {
+        let base = state.base_url.as_deref().unwrap_or_default();
+        return Ok(Redirect::temporary(&format!("{base}{}", uri.path())).into_response());
+    };
+
+    let cwd = remove_first_component(&path).display().to_string();
+    let data = IndexData {
+        entry: &[],
+        maybe_truncated: false,
+        count: 0,
+        total: None,
+        is_empty: false,
+        cwd: &cwd,
+        sync: None,
+        help: None,
+        preview: Some(html),
+        show_owner: false,
+        search: state.index.is_some(),
+        thumbnails: state.serves_thumbnails(),
+        live: false,
+        fast_listing: false,
+        timezone: state.template.timezone.name(),
+        locale: negotiate(
+            &state.template.locales,
+            query.hl.as_deref(),
+            headers
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|v| v.to_str().ok()),
+        ),
+        stream_rows: None,
+        base_url: state.base_url.as_deref(),
+        canonical_url: state
+            .base_url
+            .as_deref()
+            .map(|base| format!("{base}{}?view={}", uri.path(), view.as_str())),
+        request_path,
+        version: env!("CARGO_PKG_VERSION"),
+        hostname: &state.hostname,
+        generated_at: chrono::Utc::now().timestamp(),
+    };
+    let html = state
+        .template
+        .render("index", &data)
         .context(RenderSnafu { template: "index" })?;
-    Ok(Html(html).into_response())
+    Ok(translated(state, query, Html(html).into_response()))
+}
+
+// Images too large, or not decodable, are redirected to as-is.
+#[cfg(feature = "thumbnails")]
+async fn thumbnail(
+    state: &AppState,
+    thumbnails: &Thumbnails,
+    headers: &HeaderMap,
+    uri: &Uri,
+    request_path: &str,
+    size: u32,
+) -> Result<Response, YadexError> {
+    if !thumbnails.allows(size) {
+        return Err(YadexError::InvalidThumbnailSize);
+    }
+    let path = to_relative(Path::new("."), request_path);
+    // Hidden like in listings
+    if path
+        .file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    {
+        return Err(YadexError::NotFound {
+            source: std::io::ErrorKind::NotFound.into(),
+        });
+    }
+    state.check_filesystem(&path).await?;
+    let Some(thumbnail) = thumbnails
+        .get(&state.root.join(&path), size)
+        .await
+        .context(NotFoundSnafu)?
+    else {
+        let base = state.base_url.as_deref().unwrap_or_default();
+        return Ok(Redirect::temporary(&format!("{base}{}", uri.path())).into_response());
+    };
+    let etag = format!("\"{:016x}\"", thumbnail.key);
+    if etag_matches(headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, thumbnail.content_type.to_string()),
+            (header::CACHE_CONTROL, "public, max-age=3600".to_string()),
+            (header::ETAG, etag),
+        ],
+        thumbnail.content,
+    )
+        .into_response())
 }
 
 #[derive(Debug, Snafu)]
@@ -324,6 +2650,15 @@ pub enum YadexError {
         source: Option<color_eyre::Report>,
         message: String,
     },
+    #[snafu(display("Invalid cursor"))]
+    InvalidCursor,
+    #[snafu(display("Invalid since"))]
+    InvalidSince,
+    #[cfg(feature = "thumbnails")]
+    #[snafu(display("Invalid thumbnail size"))]
+    InvalidThumbnailSize,
+    #[snafu(display("Storage is saturated"))]
+    Overloaded { retry_after: u64 },
     #[snafu(display("The template {template} failed to render"))]
     Render {
         source: RenderError,
@@ -331,18 +2666,288 @@ pub enum YadexError {
     },
 }
 
+// Request headers a response was chosen by, like Accept-Language for translated pages.
+// Attached to responses by handlers and middlewares, and sent as Vary by set_vary.
+#[derive(Clone, Default)]
+struct Negotiated(Vec<HeaderName>);
+
+fn negotiated_by(resp: &mut Response, name: HeaderName) {
+    let negotiated = resp.extensions_mut().get_or_insert_default::<Negotiated>();
+    if !negotiated.0.contains(&name) {
+        negotiated.0.push(name);
+    }
+}
+
+// Pages are in the locale from Accept-Language, unless given by ?hl or only one is supported.
+fn translated(state: &AppState, query: &ListingQuery, mut resp: Response) -> Response {
+    if query.hl.is_none() && state.template.locales.len() > 1 {
+        negotiated_by(&mut resp, header::ACCEPT_LANGUAGE);
+    }
+    resp
+}
+
+// Layered outside of negotiate_error, so that Vary is consistent across routes and errors, as
+// caches in front rely on it. Existing values are kept.
+async fn set_vary(req: Request, next: Next) -> Response {
+    let mut resp = next.run(req).await;
+    let Some(Negotiated(names)) = resp.extensions_mut().remove::<Negotiated>() else {
+        return resp;
+    };
+    let mut vary: Vec<String> = resp
+        .headers()
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    for name in names {
+        if !vary
+            .iter()
+            .any(|v| v == "*" || v.eq_ignore_ascii_case(name.as_str()))
+        {
+            vary.push(name.to_string());
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&vary.join(", ")) {
+        resp.headers_mut().insert(header::VARY, value);
+    }
+    resp
+}
+
+// Attached to error responses, for negotiate_error to render them as the client prefers.
+#[derive(Clone, Copy)]
+struct ErrorInfo {
+    // Like "not_found"
+    code: &'static str,
+    message: &'static str,
+}
+
 impl IntoResponse for YadexError {
     fn into_response(self) -> Response {
-        match &self {
-            YadexError::NotFound { .. } => "404 Not Found".into_response(),
+        let (status, code, message) = match &self {
+            YadexError::NotFound { .. } => (StatusCode::NOT_FOUND, "not_found", "404 Not Found"),
+            YadexError::InvalidCursor => {
+                (StatusCode::BAD_REQUEST, "invalid_cursor", "Invalid cursor")
+            }
+            YadexError::InvalidSince => (StatusCode::BAD_REQUEST, "invalid_since", "Invalid since"),
+            #[cfg(feature = "thumbnails")]
+            YadexError::InvalidThumbnailSize => (
+                StatusCode::BAD_REQUEST,
+                "invalid_thumbnail_size",
+                "Invalid thumbnail size",
+            ),
+            YadexError::Overloaded { retry_after } => {
+                let mut resp = (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, *retry_after)],
+                    "503 Service Unavailable",
+                )
+                    .into_response();
+                resp.extensions_mut().insert(ErrorInfo {
+                    code: "overloaded",
+                    message: "503 Service Unavailable",
+                });
+                resp.extensions_mut().insert(Audited {
+                    event: "overloaded",
+                    detail: "storage is saturated".to_string(),
+                });
+                return resp;
+            }
             YadexError::Whatever { source, message } => {
                 error!("internal error: {message}, source: {source:?}");
-                "Internal Server Error".into_response()
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "Internal Server Error",
+                )
             }
             YadexError::Render { source, .. } => {
                 error!("internal error: {self}, source: {source:?}");
-                "Internal Server Error".into_response()
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "Internal Server Error",
+                )
             }
+        };
+        let mut resp = (status, message).into_response();
+        resp.extensions_mut().insert(ErrorInfo { code, message });
+        resp
+    }
+}
+
+#[derive(PartialEq)]
+enum ErrorFormat {
+    Json,
+    Html,
+    Text,
+}
+
+// By quality of application/json and text/html in Accept. API clients not saying get JSON.
+fn error_format(accept: Option<&str>, path: &str) -> ErrorFormat {
+    let quality = |media: &str| {
+        accept
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                parts.next()?.trim().eq_ignore_ascii_case(media).then(|| {
+                    parts
+                        .find_map(|p| p.trim().strip_prefix("q="))
+                        .and_then(|q| q.parse().ok())
+                        .unwrap_or(1.0)
+                })
+            })
+            .fold(0.0f32, f32::max)
+    };
+    let (json, html) = (quality("application/json"), quality("text/html"));
+    if json > html || json == html && path.starts_with("/api/") {
+        ErrorFormat::Json
+    } else if html > json {
+        ErrorFormat::Html
+    } else {
+        ErrorFormat::Text
+    }
+}
+
+// Renders error responses as JSON for API clients and as HTML pages for browsers. Extensions for
+// outer middlewares are kept.
+async fn negotiate_error(req: Request, next: Next) -> Response {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let path = urlencoding::decode(req.uri().path())
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| req.uri().path().to_string());
+    let mut resp = next.run(req).await;
+    let Some(error) = resp.extensions().get::<ErrorInfo>().copied() else {
+        return resp;
+    };
+    let status = resp.status();
+    let retry_after = resp.headers().get(header::RETRY_AFTER).cloned();
+    negotiated_by(&mut resp, header::ACCEPT);
+    let negotiated = resp.extensions().get::<Negotiated>().cloned();
+    let audited = resp.extensions_mut().remove::<Audited>();
+    let mut resp = match error_format(accept.as_deref(), &path) {
+        ErrorFormat::Json => (
+            status,
+            Json(serde_json::json!({
+                "error": error.code,
+                "message": error.message,
+                "path": path,
+            })),
+        )
+            .into_response(),
+        ErrorFormat::Html => (
+            status,
+            Html(format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{message}</title></head>\n\
+                 <body>\n<h1>{message}</h1>\n<p>{path}</p>\n</body>\n</html>\n",
+                message = error.message,
+                path = html_escape::encode_text(&path),
+            )),
+        )
+            .into_response(),
+        ErrorFormat::Text => resp,
+    };
+    if let Some(negotiated) = negotiated {
+        resp.extensions_mut().insert(negotiated);
+    }
+    if let Some(retry_after) = retry_after {
+        resp.headers_mut().insert(header::RETRY_AFTER, retry_after);
+    }
+    if let Some(audited) = audited {
+        resp.extensions_mut().insert(audited);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PageWindow, YadexError, body_etag, decode_cursor, encode_cursor, stable_hash};
+
+    #[test]
+    fn etags_are_stable() {
+        // Clients keep ETags across upgrades of yadex, so these must never change
+        assert_eq!(body_etag(b""), "\"a8c7f832281a39c5\"");
+        assert_eq!(body_etag(b"yadex"), "\"7ba5f6b25039f8fb\"");
+        // Parts are not just concatenated
+        assert_ne!(stable_hash(&[b"ab", b"c"]), stable_hash(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for name in ["a", "with space", "中文.txt", "x/y=+"] {
+            assert_eq!(decode_cursor(&encode_cursor(name)).unwrap(), name);
+        }
+        assert!(matches!(
+            decode_cursor("not base64!"),
+            Err(YadexError::InvalidCursor)
+        ));
+        // Valid base64 of bytes that are not UTF-8
+        assert!(matches!(
+            decode_cursor("__8"),
+            Err(YadexError::InvalidCursor)
+        ));
+    }
+
+    // Pages of names read in the given order, following cursors like clients do.
+    fn pages(names: &[&str], page_size: usize) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let mut window = PageWindow::new(after, page_size);
+            for name in names {
+                window.push(name.to_string(), name.to_string());
+            }
+            let (total, page, last) = window.finish();
+            assert_eq!(total, names.len());
+            pages.push(page);
+            match last {
+                Some(last) => after = Some(decode_cursor(&encode_cursor(&last)).unwrap()),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_cover_all_names_in_order() {
+        let names = [
+            "delta", "alpha", "echo", "charlie", "bravo", "foxtrot", "golf",
+        ];
+        assert_eq!(
+            pages(&names, 3),
+            [
+                vec!["alpha", "bravo", "charlie"],
+                vec!["delta", "echo", "foxtrot"],
+                vec!["golf"],
+            ]
+        );
+        // The last page being full has no cursor after it
+        assert_eq!(pages(&names[..6], 3).len(), 2);
+        assert_eq!(
+            pages(&names, 100),
+            [{
+                let mut sorted = names.to_vec();
+                sorted.sort();
+                sorted
+            }]
+        );
+        assert_eq!(pages(&[], 3), [Vec::<String>::new()]);
+    }
+
+    #[test]
+    fn cursor_past_removed_name() {
+        // The name of the cursor is gone, so the page starts with the next one
+        let mut window = PageWindow::new(Some("bravo".to_string()), 2);
+        for name in ["alpha", "charlie", "delta", "echo"] {
+            window.push(name.to_string(), ());
         }
+        let (total, page, last) = window.finish();
+        assert_eq!((total, page.len(), last.as_deref()), (4, 2, Some("delta")));
     }
 }
@@ -0,0 +1,85 @@
+// Protecting the storage backend when it's saturated: latency of listing I/O (each opendir and stat,
+// measured on its own) is tracked, and while it's high, listings read from storage are queued with
+// a small concurrency, and rejected once the queue is full. Cached listings are not affected.
+
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::SheddingConfig;
+
+// Weight of the latest sample in the moving average, in 1/16
+const EWMA_WEIGHT: u64 = 2;
+
+pub struct LoadShedder {
+    // Per I/O operation, in microseconds
+    threshold: u64,
+    // Moving average per I/O operation, in microseconds
+    latency: AtomicU64,
+    // Listings allowed at the same time while saturated
+    semaphore: Semaphore,
+    max_queued: usize,
+    queued: AtomicUsize,
+    // Seconds, for Retry-After of rejected requests
+    retry_after: u64,
+}
+
+// Held while listing. Only limits concurrency when admitted while saturated.
+pub struct Admission<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl LoadShedder {
+    pub fn from_config(config: SheddingConfig) -> Self {
+        Self {
+            threshold: config.latency_threshold.saturating_mul(1000),
+            latency: AtomicU64::new(0),
+            semaphore: Semaphore::new(config.concurrency.max(1)),
+            max_queued: config.max_queued,
+            queued: AtomicUsize::new(0),
+            retry_after: config.retry_after,
+        }
+    }
+
+    pub fn saturated(&self) -> bool {
+        self.latency.load(Ordering::Relaxed) > self.threshold
+    }
+
+    // None if the request should be rejected.
+    pub async fn admit(&self) -> Option<Admission<'_>> {
+        if !self.saturated() {
+            return Some(Admission { _permit: None });
+        }
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Some(Admission {
+                _permit: Some(permit),
+            });
+        }
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        let permit = self.semaphore.acquire().await.ok();
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Some(Admission { _permit: permit })
+    }
+
+    // An I/O operation of listing (opendir, or stat of an entry) took elapsed.
+    pub fn record(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |latency| {
+                Some((latency * (16 - EWMA_WEIGHT) + sample * EWMA_WEIGHT) / 16)
+            });
+        let saturated = self.saturated();
+        tracing::trace!("listing I/O latency sample {sample}us, saturated: {saturated}");
+    }
+
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+}
@@ -0,0 +1,221 @@
+// Freshness of paths, refreshed periodically from an external status source.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{body::Bytes, http::Uri};
+use http_body_util::{BodyExt, Empty};
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    config::{ServiceConfig, StatusConfig, StatusFormat},
+    tunasync::JobStatus,
+};
+
+// What listings get to know about a path's freshness.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncInfo {
+    pub status: String,
+    pub last_update: i64,
+    pub last_started: i64,
+    pub last_ended: i64,
+    pub next_schedule: i64,
+    pub upstream: String,
+    pub size: String,
+}
+
+enum StatusSource {
+    File(PathBuf),
+    Http(Uri),
+}
+
+#[derive(Debug, Snafu)]
+pub enum StatusLoadError {
+    #[snafu(display("invalid status source URL {url:?}: {source}"))]
+    Url {
+        url: String,
+        source: axum::http::uri::InvalidUri,
+    },
+    #[snafu(display("unsupported status source URL {url:?}, only http:// is supported"))]
+    Scheme { url: String },
+    #[snafu(display("failed to read status from {path:?}: {source}"))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("failed to fetch status from {uri}: {source}"))]
+    Fetch {
+        uri: String,
+        source: Box<hyper_util::client::legacy::Error>,
+    },
+    #[snafu(display("timed out fetching status from {uri}"))]
+    Timeout {
+        uri: String,
+        source: tokio::time::error::Elapsed,
+    },
+    #[snafu(display("failed to fetch status from {uri}: {source}"))]
+    Body { uri: String, source: hyper::Error },
+    #[snafu(display("failed to fetch status from {uri}: HTTP {status}"))]
+    HttpStatus {
+        uri: String,
+        status: axum::http::StatusCode,
+    },
+    #[snafu(display("failed to parse status: {source}"))]
+    Parse { source: serde_json::Error },
+}
+
+// Keys are path prefixes with leading and trailing slashes, like "/debian/".
+pub type StatusMap = BTreeMap<String, SyncInfo>;
+
+pub struct StatusStore {
+    source: StatusSource,
+    format: StatusFormat,
+    refresh_interval: Duration,
+    timeout: Duration,
+    current: RwLock<Arc<StatusMap>>,
+    // Bumped when status changes, for ETags of listings
+    generation: AtomicU64,
+}
+
+pub fn normalize_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    if trimmed.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{trimmed}/")
+    }
+}
+
+impl StatusStore {
+    // Files are checked to be readable here, as they are read first only after sandboxing.
+    pub fn from_config(
+        config: StatusConfig,
+        service: &ServiceConfig,
+    ) -> Result<Self, StatusLoadError> {
+        let source = if config.source.starts_with("http://") {
+            StatusSource::Http(config.source.parse().context(UrlSnafu {
+                url: &config.source,
+            })?)
+        } else if config.source.contains("://") {
+            // Like https://, which would otherwise be taken as a file that never exists
+            return SchemeSnafu { url: config.source }.fail();
+        } else {
+            let path = PathBuf::from(config.source);
+            let startup_path = service.before_chroot(&path);
+            std::fs::File::open(&startup_path).context(ReadSnafu { path: startup_path })?;
+            StatusSource::File(path)
+        };
+        Ok(Self {
+            source,
+            format: config.format,
+            refresh_interval: Duration::from_secs(config.refresh_interval.max(1)),
+            timeout: Duration::from_secs(config.timeout.max(1)),
+            current: RwLock::new(Arc::default()),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    async fn fetch(&self) -> Result<Bytes, StatusLoadError> {
+        match &self.source {
+            StatusSource::File(path) => Ok(tokio::fs::read(path)
+                .await
+                .context(ReadSnafu { path })?
+                .into()),
+            StatusSource::Http(uri) => {
+                let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+                // A hung upstream would otherwise stall refreshing forever
+                let resp = tokio::time::timeout(self.timeout, client.get(uri.clone()))
+                    .await
+                    .context(TimeoutSnafu {
+                        uri: uri.to_string(),
+                    })?
+                    .map_err(Box::new)
+                    .context(FetchSnafu {
+                        uri: uri.to_string(),
+                    })?;
+                if !resp.status().is_success() {
+                    return HttpStatusSnafu {
+                        uri: uri.to_string(),
+                        status: resp.status(),
+                    }
+                    .fail();
+                }
+                Ok(
+                    tokio::time::timeout(self.timeout, resp.into_body().collect())
+                        .await
+                        .context(TimeoutSnafu {
+                            uri: uri.to_string(),
+                        })?
+                        .context(BodySnafu {
+                            uri: uri.to_string(),
+                        })?
+                        .to_bytes(),
+                )
+            }
+        }
+    }
+
+    async fn load(&self) -> Result<StatusMap, StatusLoadError> {
+        let content = self.fetch().await?;
+        Ok(match self.format {
+            StatusFormat::Tunasync => serde_json::from_slice::<Vec<JobStatus>>(&content)
+                .context(ParseSnafu)?
+                .into_iter()
+                .map(|job| (job.prefix(), SyncInfo::from(job)))
+                .collect(),
+            StatusFormat::Prefix => serde_json::from_slice::<StatusMap>(&content)
+                .context(ParseSnafu)?
+                .into_iter()
+                .map(|(prefix, info)| (normalize_prefix(&prefix), info))
+                .collect(),
+        })
+    }
+
+    // Keeps the last known status when refreshing fails.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(store.refresh_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match store.load().await {
+                    Ok(map) => {
+                        let mut current = store.current.write().unwrap();
+                        if **current != map {
+                            *current = Arc::new(map);
+                            store.generation.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(e) => tracing::warn!("failed to refresh status: {e}"),
+                }
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> Arc<StatusMap> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
+
+// Status of the longest prefix covering the path.
+pub fn longest_prefix<'a>(map: &'a StatusMap, path: &str) -> Option<&'a SyncInfo> {
+    map.range(..=path.to_string())
+        .rev()
+        .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .map(|(_, info)| info)
+}
@@ -0,0 +1,29 @@
+// Built-in themes, embedded into the binary so that no template files are needed.
+
+use crate::config::Theme;
+
+pub struct ThemeFiles {
+    pub index: &'static str,
+    pub row: &'static str,
+    pub css: &'static str,
+}
+
+pub fn files(theme: Theme) -> ThemeFiles {
+    match theme {
+        Theme::Classic => ThemeFiles {
+            index: include_str!("themes/classic/index.html"),
+            row: include_str!("themes/classic/row.html"),
+            css: include_str!("themes/classic/style.css"),
+        },
+        Theme::Modern => ThemeFiles {
+            index: include_str!("themes/modern/index.html"),
+            row: include_str!("themes/modern/row.html"),
+            css: include_str!("themes/modern/style.css"),
+        },
+        Theme::Dense => ThemeFiles {
+            index: include_str!("themes/dense/index.html"),
+            row: include_str!("themes/dense/row.html"),
+            css: include_str!("themes/dense/style.css"),
+        },
+    }
+}
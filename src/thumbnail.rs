@@ -0,0 +1,204 @@
+// Downscaled thumbnails of images for ?thumb=SIZE, cached on disk.
+
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Cursor},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use image::{DynamicImage, ImageFormat, ImageReader, codecs::jpeg::JpegEncoder};
+use tokio::sync::Semaphore;
+
+use crate::config::ThumbnailsConfig;
+
+// Between checking size of the cache
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+// Temporary files (".key.n") older than this are left behind by failed writes, younger ones are
+// being written.
+const TEMP_GRACE: Duration = Duration::from_secs(3600);
+
+pub struct Thumbnails {
+    cache_dir: PathBuf,
+    // 0 for unlimited
+    max_cache_size: u64,
+    // Allowed values of ?thumb, so that the cache is bounded
+    sizes: Vec<u32>,
+    max_source_size: u64,
+    // Limits images being decoded at the same time
+    semaphore: Semaphore,
+    // For unique names of files being written
+    counter: AtomicU64,
+}
+
+pub struct Thumbnail {
+    pub content: Vec<u8>,
+    pub content_type: &'static str,
+    // Changes when the image changes
+    pub key: u64,
+}
+
+fn content_type(content: &[u8]) -> &'static str {
+    match image::guess_format(content) {
+        Ok(ImageFormat::Png) => "image/png",
+        _ => "image/jpeg",
+    }
+}
+
+// PNG for images with transparency, JPEG otherwise.
+fn encode(image: &DynamicImage) -> image::ImageResult<Vec<u8>> {
+    let mut content = Vec::new();
+    if image.color().has_alpha() {
+        image.write_to(&mut Cursor::new(&mut content), ImageFormat::Png)?;
+    } else {
+        JpegEncoder::new_with_quality(&mut content, 80).encode_image(&image.to_rgb8())?;
+    }
+    Ok(content)
+}
+
+impl Thumbnails {
+    pub fn from_config(config: ThumbnailsConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.cache_dir)?;
+        Ok(Self {
+            cache_dir: config.cache_dir,
+            max_cache_size: config.max_cache_size,
+            sizes: config.sizes,
+            max_source_size: config.max_source_size,
+            semaphore: Semaphore::new(config.concurrency.max(1)),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    // Thumbnails of changed images are keyed differently, so the old ones are left behind until
+    // pruned here.
+    pub fn spawn_prune(self: &Arc<Self>) {
+        if self.max_cache_size == 0 {
+            return;
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let pruner = this.clone();
+                match tokio::task::spawn_blocking(move || pruner.prune()).await {
+                    Ok(Ok((size, removed))) => tracing::debug!(
+                        "thumbnail cache takes {size} bytes, {removed} thumbnails removed"
+                    ),
+                    Ok(Err(e)) => tracing::warn!("failed to prune thumbnail cache: {e}"),
+                    Err(e) => tracing::warn!("failed to prune thumbnail cache: {e}"),
+                }
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+
+    // Removes least recently used thumbnails (by atime, which is coarse with relatime, but enough)
+    // until the cache is under 90% of max_cache_size. Returns size left, and number removed.
+    // Temporary files being written are skipped, while stale ones are removed first.
+    fn prune(&self) -> io::Result<(u64, usize)> {
+        let mut files: Vec<_> = fs::read_dir(&self.cache_dir)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                let used = match entry.file_name().as_encoded_bytes().starts_with(b".") {
+                    true if meta.modified().ok()?.elapsed().ok()? < TEMP_GRACE => return None,
+                    true => SystemTime::UNIX_EPOCH,
+                    false => meta.accessed().or_else(|_| meta.modified()).ok()?,
+                };
+                meta.is_file().then(|| (used, meta.len(), entry.path()))
+            })
+            .collect();
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        if size <= self.max_cache_size {
+            return Ok((size, 0));
+        }
+        files.sort_unstable_by_key(|(used, _, _)| *used);
+        let target = self.max_cache_size / 10 * 9;
+        let mut removed = 0;
+        for (_, len, path) in files {
+            if size <= target {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    size -= len;
+                    removed += 1;
+                }
+                Err(e) => tracing::debug!("failed to remove thumbnail {path:?}: {e}"),
+            }
+        }
+        Ok((size, removed))
+    }
+
+    pub fn allows(&self, size: u32) -> bool {
+        self.sizes.contains(&size)
+    }
+
+    // None if the file is not a supported image, or is too large.
+    pub async fn get(&self, path: &Path, size: u32) -> io::Result<Option<Thumbnail>> {
+        let metadata = tokio::fs::metadata(path).await?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if metadata.len() > self.max_source_size {
+            return Ok(None);
+        }
+        let mut hasher = DefaultHasher::new();
+        (
+            path,
+            metadata.len(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+            size,
+        )
+            .hash(&mut hasher);
+        let key = hasher.finish();
+        let cached = self.cache_dir.join(format!("{key:016x}"));
+        if let Ok(content) = tokio::fs::read(&cached).await {
+            return Ok(Some(Thumbnail {
+                content_type: content_type(&content),
+                content,
+                key,
+            }));
+        }
+
+        let _permit = self.semaphore.acquire().await.unwrap();
+        let source = path.to_path_buf();
+        let content = tokio::task::spawn_blocking(move || {
+            let image = ImageReader::open(&source)?.with_guessed_format()?.decode();
+            Ok::<_, io::Error>(
+                image
+                    .and_then(|image| encode(&image.thumbnail(size, size)))
+                    .inspect_err(|e| tracing::debug!("no thumbnail for {source:?}: {e}"))
+                    .ok(),
+            )
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let Some(content) = content else {
+            return Ok(None);
+        };
+
+        // Written aside and renamed, so that readers never see partial files
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let temp = self.cache_dir.join(format!(".{key:016x}.{n}"));
+        let written = match tokio::fs::write(&temp, &content).await {
+            Ok(()) => tokio::fs::rename(&temp, &cached).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::warn!("failed to cache thumbnail of {path:?}: {e}");
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        Ok(Some(Thumbnail {
+            content_type: content_type(&content),
+            content,
+            key,
+        }))
+    }
+}
@@ -0,0 +1,45 @@
+// Job status format produced by tunasync.
+
+use serde::Deserialize;
+
+use crate::status::SyncInfo;
+
+// An element of the JSON array returned by tunasync manager's /jobs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub status: String,
+    #[serde(default)]
+    pub last_update_ts: i64,
+    #[serde(default)]
+    pub last_started_ts: i64,
+    #[serde(default)]
+    pub last_ended_ts: i64,
+    #[serde(default)]
+    pub next_schedule_ts: i64,
+    #[serde(default)]
+    pub upstream: String,
+    #[serde(default)]
+    pub size: String,
+}
+
+impl JobStatus {
+    // Jobs are named after top-level directories.
+    pub fn prefix(&self) -> String {
+        format!("/{}/", self.name)
+    }
+}
+
+impl From<JobStatus> for SyncInfo {
+    fn from(job: JobStatus) -> Self {
+        Self {
+            status: job.status,
+            last_update: job.last_update_ts,
+            last_started: job.last_started_ts,
+            last_ended: job.last_ended_ts,
+            next_schedule: job.next_schedule_ts,
+            upstream: job.upstream,
+            size: job.size,
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// Rules by User-Agent, against misbehaving scrapers and broken download managers that retry
+// listings in tight loops.
+
+use std::{
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use regex::Regex;
+
+use crate::config::{UserAgentAction, UserAgentsConfig};
+
+struct Rule {
+    pattern: Regex,
+    action: UserAgentAction,
+    requests: u32,
+    interval: u64,
+    // Requests of each client in the current interval, for "throttle"
+    counters: moka::sync::Cache<IpAddr, Arc<AtomicU32>>,
+}
+
+pub enum Verdict {
+    Pass,
+    Block,
+    // Seconds until allowed again, at most
+    Throttled(u64),
+    Plain,
+}
+
+pub struct UserAgentRules {
+    rules: Vec<Rule>,
+}
+
+impl UserAgentRules {
+    pub fn from_config(config: UserAgentsConfig) -> Result<Self, regex::Error> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: Regex::new(&rule.pattern)?,
+                    action: rule.action,
+                    requests: rule.requests,
+                    interval: rule.interval.max(1),
+                    // Intervals start at the first request of clients
+                    counters: moka::sync::Cache::builder()
+                        .max_capacity(100_000)
+                        .time_to_live(Duration::from_secs(rule.interval.max(1)))
+                        .build(),
+                })
+            })
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(Self { rules })
+    }
+
+    // Whether any rule throttles, which needs to tell clients apart.
+    pub fn throttles(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule.action, UserAgentAction::Throttle))
+    }
+
+    // Unknown clients are not throttled.
+    pub fn check(&self, user_agent: &str, client: Option<IpAddr>) -> Verdict {
+        let Some(rule) = self.rules.iter().find(|r| r.pattern.is_match(user_agent)) else {
+            return Verdict::Pass;
+        };
+        match rule.action {
+            UserAgentAction::Block => Verdict::Block,
+            UserAgentAction::Plain => Verdict::Plain,
+            UserAgentAction::Throttle => {
+                let Some(client) = client else {
+                    return Verdict::Pass;
+                };
+                let count = rule
+                    .counters
+                    .get_with(client, Default::default)
+                    .fetch_add(1, Ordering::Relaxed);
+                match count < rule.requests {
+                    true => Verdict::Pass,
+                    false => Verdict::Throttled(rule.interval),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// Watching directories for changes with inotify.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+use tokio::sync::broadcast;
+
+pub struct DirWatcher {
+    watched: Mutex<Watched>,
+    changes: broadcast::Sender<PathBuf>,
+    entries: broadcast::Sender<EntryChange>,
+}
+
+// A directory is watched while it's cached, or has live subscribers.
+struct Watched {
+    watcher: RecommendedWatcher,
+    // Relative paths are resolved against it
+    root: PathBuf,
+    cached: HashSet<PathBuf>,
+    live: HashMap<PathBuf, usize>,
+}
+
+impl Watched {
+    fn add(&mut self, path: &Path) {
+        let path = self.root.join(path);
+        if let Err(e) = self.watcher.watch(&path, RecursiveMode::NonRecursive) {
+            tracing::warn!("failed to watch {path:?}: {e}");
+        }
+    }
+
+    fn remove_if_unused(&mut self, path: &Path) {
+        if !self.cached.contains(path) && !self.live.contains_key(path) {
+            // Fails if the directory is gone, which is fine
+            let _ = self.watcher.unwatch(&self.root.join(path));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Add,
+    Remove,
+    Modify,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Add => "add",
+            ChangeKind::Remove => "remove",
+            ChangeKind::Modify => "modify",
+        }
+    }
+}
+
+// A change of an entry in a watched directory.
+#[derive(Clone)]
+pub struct EntryChange {
+    // Like "./debian/dists"
+    pub dir: PathBuf,
+    pub name: String,
+    pub kind: ChangeKind,
+}
+
+// Watched paths are made absolute by notify. Turn them back to "./relative/path" like what handlers use.
+fn to_relative(base: &Path, path: &Path) -> Option<PathBuf> {
+    Some(Path::new(".").join(path.strip_prefix(base).ok()?))
+}
+
+fn entry_change(path: &Path, kind: ChangeKind) -> Option<EntryChange> {
+    Some(EntryChange {
+        dir: path.parent()?.to_path_buf(),
+        name: path.file_name()?.to_string_lossy().into_owned(),
+        kind,
+    })
+}
+
+// Renames come as From and To, followed by Both if both sides are watched, which is then skipped.
+fn entry_changes(kind: &EventKind, paths: &[PathBuf]) -> Vec<EntryChange> {
+    let kind = match kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            ChangeKind::Add
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            ChangeKind::Remove
+        }
+        EventKind::Modify(ModifyKind::Name(_)) => return Vec::new(),
+        EventKind::Modify(_) => ChangeKind::Modify,
+        _ => return Vec::new(),
+    };
+    paths
+        .iter()
+        .filter_map(|path| entry_change(path, kind))
+        .collect()
+}
+
+impl DirWatcher {
+    // Paths are relative to root, which is where files are as seen after chroot.
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        let base = root.clone();
+        let (changes, _) = broadcast::channel(1024);
+        let (entries, _) = broadcast::channel(1024);
+        let sender = changes.clone();
+        let entry_sender = entries.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("watcher error: {e}");
+                    return;
+                }
+            };
+            let paths: Vec<PathBuf> = event
+                .paths
+                .iter()
+                .filter_map(|p| to_relative(&base, p))
+                .collect();
+            for path in &paths {
+                // Both the directory containing the changed entry, and the entry itself if it's a
+                // watched directory being removed or renamed
+                if let Some(parent) = path.parent() {
+                    let _ = sender.send(parent.to_path_buf());
+                }
+                let _ = sender.send(path.clone());
+            }
+            for change in entry_changes(&event.kind, &paths) {
+                let _ = entry_sender.send(change);
+            }
+        })
+        .map_err(io::Error::other)?;
+        Ok(Self {
+            watched: Mutex::new(Watched {
+                watcher,
+                root,
+                cached: HashSet::new(),
+                live: HashMap::new(),
+            }),
+            changes,
+            entries,
+        })
+    }
+
+    pub fn watch(&self, path: &Path) {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.cached.insert(path.to_path_buf()) && !watched.live.contains_key(path) {
+            watched.add(path);
+        }
+    }
+
+    pub fn unwatch(&self, path: &Path) {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.cached.remove(path) {
+            watched.remove_if_unused(path);
+        }
+    }
+
+    // Watches a directory until the returned guard is dropped, for live subscribers.
+    pub fn watch_live(self: &Arc<Self>, path: &Path) -> LiveWatch {
+        let mut watched = self.watched.lock().unwrap();
+        let count = watched.live.entry(path.to_path_buf()).or_default();
+        *count += 1;
+        if *count == 1 && !watched.cached.contains(path) {
+            watched.add(path);
+        }
+        LiveWatch {
+            watcher: self.clone(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    // Receives paths of directories which might have changed.
+    pub fn subscribe(&self) -> broadcast::Receiver<PathBuf> {
+        self.changes.subscribe()
+    }
+
+    // Receives changes of entries in watched directories.
+    pub fn subscribe_entries(&self) -> broadcast::Receiver<EntryChange> {
+        self.entries.subscribe()
+    }
+}
+
+pub struct LiveWatch {
+    watcher: Arc<DirWatcher>,
+    path: PathBuf,
+}
+
+impl Drop for LiveWatch {
+    fn drop(&mut self) {
+        let mut watched = self.watcher.watched.lock().unwrap();
+        if let Some(count) = watched.live.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                watched.live.remove(&self.path);
+                watched.remove_if_unused(&self.path);
+            }
+        }
+    }
+}